@@ -0,0 +1,59 @@
+// Feeds arbitrary bytes to the MBLP parsers, run with `cargo fuzz run mblp`
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/controller/mblp.rs"]
+#[allow(dead_code)]
+mod mblp;
+
+use mblp::{Code, Command, Decoder, Response};
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever is accepted must read back the same after serializing it
+    if let Ok(command) = Command::parse(data) {
+        let (code, source, destination) = (command.code, command.source, command.destination);
+        let payload = command.payload().to_vec();
+
+        let mut buffer = [0; 256];
+        let len = command
+            .serialize(&mut buffer)
+            .expect("parsed command serializes");
+        let again = Command::parse(&buffer[0..len]).expect("serialized command parses");
+        assert_eq!(again.code, code);
+        assert_eq!(again.source, source);
+        assert_eq!(again.destination, destination);
+        assert_eq!(again.payload(), &payload[..]);
+    }
+    if let Ok(response) = Response::parse(data) {
+        let mut buffer = [0; 256];
+        let len = response
+            .serialize(&mut buffer)
+            .expect("parsed response serializes");
+        let again = Response::parse(&buffer[0..len]).expect("serialized response parses");
+        assert_eq!(again.status, response.status);
+        assert_eq!(again.source, response.source);
+        assert_eq!(again.destination, response.destination);
+        assert_eq!(again.payload(), response.payload());
+    }
+
+    let mut decoder = Decoder::new();
+    decoder.push(data);
+    while decoder.try_next_frame().is_some() {}
+
+    // Build packets from the input and read them back
+    if let [code_high, code_low, available, payload @ ..] = data {
+        let code = Code::from((*code_high, *code_low));
+        let mut buffer = vec![0; *available as usize];
+
+        if let Ok(len) = Command::new(code, [1; 4], [2; 4], payload).serialize(&mut buffer) {
+            let command = Command::parse(&buffer[0..len]).expect("serialized command parses");
+            assert_eq!(u16::from(command.code), u16::from(code));
+            assert_eq!(command.payload(), payload);
+        }
+        if let Ok(len) = Response::ok([1; 4], [2; 4], payload).serialize(&mut buffer) {
+            let response = Response::parse(&buffer[0..len]).expect("serialized response parses");
+            assert_eq!(response.payload(), payload);
+        }
+    }
+});
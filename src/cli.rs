@@ -0,0 +1,279 @@
+// Headless mode for scripted test stations
+//
+// Every command prints `key=value` lines on stdout and exits with a non-zero
+// code on failure, so it can be driven by a shell or MES script:
+// 1 when the operation fails, 2 on usage errors, 3 when the port cannot be opened.
+// `replay` prints an `entry=` line for every frame of a capture before its result.
+
+use crate::controller::job::Unobserved;
+use crate::controller::plan::TestPlan;
+use crate::controller::{capture, device, report, serial, Error};
+use std::path::PathBuf;
+
+pub const COMMANDS: [&str; 7] = [
+    "list-ports",
+    "read-address",
+    "set-address",
+    "read-fw",
+    "read-cpu-id",
+    "test",
+    "replay",
+];
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_CONNECTION: i32 = 3;
+
+const USAGE: &str = "Uso:
+    sinottico list-ports
+    sinottico read-address --port <PORTA> [--address <HEX>]
+    sinottico set-address --port <PORTA> --address <HEX>
+    sinottico read-fw --port <PORTA> [--address <HEX>]
+    sinottico read-cpu-id --port <PORTA> [--address <HEX>]
+    sinottico test --port <PORTA> --address <HEX> [--plan <FILE>] [--run-all]
+    sinottico replay --capture <FILE>
+
+Con --capture i comandi sulla porta salvano il traffico nel file indicato.
+Con --run-all il collaudo prosegue dopo un passo fallito e riporta tutti i guasti.";
+
+struct Options {
+    port: Option<String>,
+    address: Option<u32>,
+    plan: Option<PathBuf>,
+    capture: Option<PathBuf>,
+    run_all: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            port: None,
+            address: None,
+            plan: None,
+            capture: None,
+            run_all: false,
+        };
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            if arg == "--run-all" {
+                options.run_all = true;
+                continue;
+            }
+
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("Valore mancante per {}", arg))?;
+
+            match arg.as_str() {
+                "--port" => options.port = Some(value.clone()),
+                "--plan" => options.plan = Some(PathBuf::from(value)),
+                "--capture" => options.capture = Some(PathBuf::from(value)),
+                "--address" => {
+                    options.address = Some(
+                        u32::from_str_radix(value, 16)
+                            .map_err(|_| format!("Indirizzo non valido: {}", value))?,
+                    )
+                }
+                _ => return Err(format!("Opzione sconosciuta: {}", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn open_port(&self) -> Result<device::Port, String> {
+        let name = self
+            .port
+            .as_ref()
+            .ok_or_else(|| String::from("Porta non specificata"))?;
+        let settings = serial::TransportSettings::load_or_default()?;
+        let port_settings = serial::load_port_settings()
+            .remove(name)
+            .unwrap_or_default();
+        let mut link = serial::Link::open(name, &port_settings, settings)
+            .map_err(|e| format!("Errore di connessione: {}", e))?;
+        if let Some(path) = &self.capture {
+            link.start_capture(path)?;
+        }
+        Ok(link)
+    }
+
+    fn address(&self) -> Result<[u8; 4], String> {
+        self.address
+            .map(u32::to_be_bytes)
+            .ok_or_else(|| String::from("Indirizzo non specificato"))
+    }
+
+    fn plan(&self) -> Result<TestPlan, String> {
+        let mut plan = match &self.plan {
+            Some(path) => TestPlan::load(path)?,
+            None => TestPlan::load_or_default()?,
+        };
+        plan.run_all |= self.run_all;
+        Ok(plan)
+    }
+
+    fn address_or_broadcast(&self) -> [u8; 4] {
+        self.address.unwrap_or(0).to_be_bytes()
+    }
+}
+
+pub fn is_command(arg: &str) -> bool {
+    COMMANDS.contains(&arg)
+}
+
+/// Runs the command in `args[0]` and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let command = args[0].as_str();
+    let options = match Options::parse(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => return usage_error(e),
+    };
+
+    if command == "list-ports" {
+        for port in serial::get_serial_ports() {
+            println!("port={}", port);
+        }
+        return 0;
+    }
+
+    if command == "replay" {
+        return match &options.capture {
+            Some(path) => replay(path),
+            None => usage_error(String::from("File di cattura non specificato")),
+        };
+    }
+
+    let mut port = match options.open_port() {
+        Ok(port) => port,
+        Err(e) if options.port.is_none() => return usage_error(e),
+        Err(e) => {
+            println!("result=error");
+            println!("error={}", e);
+            return EXIT_CONNECTION;
+        }
+    };
+
+    let result = match command {
+        "read-address" => device::read_serial_number(&mut port, options.address_or_broadcast())
+            .map(|sn| vec![format!("address={:08X}", sn)])
+            .map_err(|e| failure_lines(None, &e)),
+        "read-fw" => device::read_firmware_version(&mut port, options.address_or_broadcast())
+            .map(|version| vec![format!("version={}", version)])
+            .map_err(|e| failure_lines(None, &e)),
+        "read-cpu-id" => device::read_cpu_id(&mut port, options.address_or_broadcast())
+            .map(|cpu_id| vec![format!("cpu_id={}", cpu_id)])
+            .map_err(|e| failure_lines(None, &e)),
+        "set-address" => match options.address() {
+            Ok(address) => device::set_serial_number(&mut port, address)
+                .map(|()| {
+                    let serial = u32::from_be_bytes(address);
+                    let (cpu_id, saved) = report::record_assignment(&mut port, serial);
+                    if let Err(e) = saved {
+                        println!("report_error={}", e);
+                    }
+                    let mut lines = vec![format!("address={:08X}", serial)];
+                    match cpu_id {
+                        Ok(cpu_id) => lines.push(format!("cpu_id={}", cpu_id)),
+                        Err(e) => lines.push(format!("cpu_id_error={}", e)),
+                    }
+                    lines
+                })
+                .map_err(|e| failure_lines(None, &e)),
+            Err(e) => return usage_error(e),
+        },
+        "test" => match options.address().and_then(|a| Ok((a, options.plan()?))) {
+            Ok((address, plan)) => {
+                let (outcome, cpu_id, saved) =
+                    report::test_and_record(&mut port, address, &plan, &mut Unobserved);
+                if let Some(Err(e)) = cpu_id {
+                    println!("cpu_id_error={}", e);
+                }
+                if let Err(e) = saved {
+                    println!("report_error={}", e);
+                }
+                match &outcome.safe_state {
+                    Some(Ok(())) => println!("relays_off=ok"),
+                    Some(Err(e)) => println!("relays_off_error={}", e),
+                    None => (),
+                }
+                outcome.result().map(|()| vec![]).map_err(|f| {
+                    let mut lines = failure_lines(Some(&f.step), &f.error);
+                    if outcome.failures.len() > 1 || !outcome.faults.is_empty() {
+                        lines.push(format!("failures={}", outcome.failures.len()));
+                        for failure in &outcome.failures {
+                            lines.push(format!("failure={}", failure));
+                        }
+                        for fault in &outcome.faults {
+                            lines.push(format!("fault={}", fault));
+                        }
+                    }
+                    lines
+                })
+            }
+            Err(e) => return usage_error(e),
+        },
+        _ => return usage_error(format!("Comando sconosciuto: {}", command)),
+    };
+
+    match result {
+        Ok(lines) => {
+            println!("result=ok");
+            for line in lines {
+                println!("{}", line);
+            }
+            0
+        }
+        Err(lines) => {
+            println!("result=fail");
+            for line in lines {
+                println!("{}", line);
+            }
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn replay(path: &std::path::Path) -> i32 {
+    let chunks = match capture::load(path) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            println!("result=error");
+            println!("error={}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let entries = capture::transcript(&chunks);
+    for entry in &entries {
+        println!("entry={}", entry);
+    }
+
+    let problems = entries.iter().filter(|e| e.problem.is_some()).count();
+    println!("result={}", if problems == 0 { "ok" } else { "fail" });
+    println!("frames={}", entries.len());
+    println!("problems={}", problems);
+    if problems == 0 {
+        0
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+fn failure_lines(step: Option<&str>, error: &Error) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(step) = step {
+        lines.push(format!("step={}", step));
+    }
+    lines.push(format!("error_kind={}", error.kind()));
+    lines.push(format!("error={}", error));
+    lines
+}
+
+fn usage_error(msg: String) -> i32 {
+    println!("result=error");
+    println!("error={}", msg);
+    eprintln!("{}", USAGE);
+    EXIT_USAGE
+}
@@ -0,0 +1,256 @@
+// Serial number allocation from a counter file shared by the test stations
+//
+// The file describes the current batch, e.g.
+//     { "prefix": "140301", "first": 0, "last": 255, "next": 0 }
+// gives the serials 14030100 to 140301FF. A number is only consumed once it
+// has been written to a board and read back.
+
+use super::json;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const SERIAL_FILE: &str = "matricole.json";
+
+/// Long enough for another station to write and verify a serial
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Batch {
+    /// Leading hex digits shared by every serial of the batch
+    pub prefix: String,
+    pub first: u32,
+    pub last: u32,
+    pub next: u32,
+}
+
+impl Batch {
+    fn serial(&self, counter: u32) -> Result<u32, String> {
+        if self.prefix.len() >= 8 {
+            return Err(format!("Prefisso troppo lungo: {}", self.prefix));
+        }
+
+        let prefix = if self.prefix.is_empty() {
+            0
+        } else {
+            u32::from_str_radix(&self.prefix, 16)
+                .map_err(|_| format!("Prefisso non valido: {}", self.prefix))?
+        };
+        let bits = 4 * (8 - self.prefix.len() as u32);
+
+        if (counter as u64) >> bits != 0 {
+            return Err(format!(
+                "Il contatore {:X} non sta nelle cifre dopo il prefisso {}",
+                counter, self.prefix
+            ));
+        }
+
+        Ok(((prefix as u64) << bits) as u32 | counter)
+    }
+
+    fn next_serial(&self) -> Result<u32, String> {
+        if self.next < self.first || self.next > self.last {
+            return Err(format!(
+                "Lotto esaurito ({:X} fuori da {:X} - {:X})",
+                self.next, self.first, self.last
+            ));
+        }
+        self.serial(self.next)
+    }
+}
+
+pub struct Allocator {
+    path: PathBuf,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new(Path::new(SERIAL_FILE))
+    }
+}
+
+impl Allocator {
+    pub fn new(path: &Path) -> Self {
+        Allocator {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Next free serial number, without consuming it. Only for display: another
+    /// station may take it before it is assigned.
+    pub fn peek(&self) -> Result<u32, String> {
+        self.load()?.next_serial()
+    }
+
+    /// Writes the next free serial number with `write` and consumes it once
+    /// written. The counter stays locked meanwhile, so no other station can
+    /// write the same number to its board.
+    pub fn assign<F>(&self, write: F) -> Result<u32, String>
+    where
+        F: FnOnce(u32) -> Result<(), String>,
+    {
+        let _lock = Lock::acquire(&self.path.with_extension("lock"))?;
+
+        let mut batch = self.load()?;
+        let serial = batch.next_serial()?;
+        write(serial)?;
+
+        batch.next += 1;
+        self.store(&batch)
+            .map_err(|e| format!("Matricola {:08X} scritta ma non registrata: {}", serial, e))?;
+        Ok(serial)
+    }
+
+    fn load(&self) -> Result<Batch, String> {
+        json::load(&self.path)
+    }
+
+    fn store(&self, batch: &Batch) -> Result<(), String> {
+        let text = serde_json::to_string_pretty(batch).map_err(|e| e.to_string())?;
+        let temporary = self.path.with_extension("tmp");
+
+        fs::write(&temporary, text)
+            .and_then(|()| fs::rename(&temporary, &self.path))
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Exclusive access to the counter file, released when dropped or when the
+/// station holding it dies.
+struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    fn acquire(path: &Path) -> Result<Self, String> {
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let start = Instant::now();
+
+        // The file is left in place: removing it would let two stations lock
+        // different files with the same name
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(error)?;
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Lock { _file: file }),
+                Err(TryLockError::WouldBlock) if start.elapsed() > LOCK_TIMEOUT => {
+                    return Err(format!(
+                        "{} bloccato da un'altra postazione",
+                        path.display()
+                    ))
+                }
+                Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(50)),
+                Err(TryLockError::Error(e)) => return Err(error(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(prefix: &str, first: u32, last: u32, next: u32) -> Batch {
+        Batch {
+            prefix: prefix.into(),
+            first,
+            last,
+            next,
+        }
+    }
+
+    #[test]
+    fn serial_fills_the_digits_after_the_prefix() {
+        assert_eq!(batch("140301", 0, 255, 0).serial(0x2A), Ok(0x1403012A));
+        assert_eq!(batch("14", 0, 255, 0).serial(0x2A), Ok(0x1400002A));
+        assert_eq!(batch("", 0, 255, 0).serial(0x2A), Ok(0x0000002A));
+        assert_eq!(batch("1403010", 0, 15, 0).serial(0xF), Ok(0x1403010F));
+    }
+
+    #[test]
+    fn serial_rejects_bad_prefixes() {
+        assert!(batch("14030100", 0, 0, 0).serial(0).is_err());
+        assert!(batch("14G3", 0, 0, 0).serial(0).is_err());
+    }
+
+    #[test]
+    fn counter_overflowing_the_prefix() {
+        assert!(batch("140301", 0, 0x1FF, 0).serial(0xFF).is_ok());
+        assert!(batch("140301", 0, 0x1FF, 0).serial(0x100).is_err());
+        assert!(batch("1403010", 0, 0x1F, 0).serial(0x10).is_err());
+    }
+
+    #[test]
+    fn next_serial_within_the_batch() {
+        assert_eq!(
+            batch("140301", 0x10, 0x20, 0x10).next_serial(),
+            Ok(0x14030110)
+        );
+        assert_eq!(
+            batch("140301", 0x10, 0x20, 0x20).next_serial(),
+            Ok(0x14030120)
+        );
+    }
+
+    #[test]
+    fn batch_exhausted() {
+        assert!(batch("140301", 0x10, 0x20, 0x21).next_serial().is_err());
+        assert!(batch("140301", 0x10, 0x20, 0x0F).next_serial().is_err());
+    }
+
+    fn counter_file(name: &str, next: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        let text = serde_json::to_string(&batch("1403", 0, 0xFFFF, next)).unwrap();
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn failed_write_does_not_consume() {
+        let path = counter_file("matricole-failed", 5);
+        let allocator = Allocator::new(&path);
+
+        assert!(allocator.assign(|_| Err("non scritta".into())).is_err());
+        assert_eq!(allocator.assign(|_| Ok(())), Ok(0x14030005));
+        assert_eq!(allocator.peek(), Ok(0x14030006));
+    }
+
+    #[test]
+    fn concurrent_stations_get_distinct_serials() {
+        let path = counter_file("matricole-concurrent", 0);
+
+        let stations: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let allocator = Allocator::new(&path);
+                    (0..5)
+                        .map(|_| {
+                            allocator
+                                .assign(|_| {
+                                    thread::sleep(Duration::from_millis(5));
+                                    Ok(())
+                                })
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut serials: Vec<u32> = stations
+            .into_iter()
+            .flat_map(|station| station.join().unwrap())
+            .collect();
+        serials.sort();
+        serials.dedup();
+        assert_eq!(serials, (0x14030000..0x14030014).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,476 @@
+// Raw serial sessions saved to file, to be attached to bug reports and
+// decoded later
+//
+// The file is text, with one line for every chunk of bytes written to or read
+// from the port:
+//     # sinottico capture 1
+//     2026-10-17T10:00:00.123456Z TX 02 01 13 00 14 03 01 00 00 00 00 00 FF 04 ...
+//     2026-10-17T10:00:00.135102Z RX 02 01 12 00 00 00 00 00 14 03 01 00 00 ...
+
+use super::mblp::{Code, Command, Decoder, FrameError, Response};
+use super::traffic::{time_of_day, to_hex, Direction};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub const HEADER: &str = "# sinottico capture 1";
+
+/// Bytes that went through the port in a single read or write
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub timestamp: OffsetDateTime,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+pub struct Writer {
+    file: File,
+}
+
+impl Writer {
+    /// Opens `path` for writing, appending to the capture it already holds.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let error = |e: io::Error| format!("{}: {}", path.display(), e);
+
+        let existing = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(error(e)),
+        };
+        if !existing.is_empty() && existing.lines().next() != Some(HEADER) {
+            return Err(format!("{}: non e' un file di cattura", path.display()));
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(error)?;
+        if existing.is_empty() {
+            writeln!(file, "{}", HEADER).map_err(error)?;
+        }
+        Ok(Writer { file })
+    }
+
+    pub fn write(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let timestamp = OffsetDateTime::from(SystemTime::now())
+            .format(&Rfc3339)
+            .unwrap_or_default();
+
+        write!(self.file, "{} {}", timestamp, direction)?;
+        for b in bytes {
+            write!(self.file, " {:02X}", b)?;
+        }
+        writeln!(self.file)
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Chunk>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut lines = text.lines().enumerate();
+
+    match lines.next() {
+        Some((_, HEADER)) => (),
+        _ => return Err(format!("{}: non e' un file di cattura", path.display())),
+    }
+
+    lines
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            parse_line(line).map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<Chunk, String> {
+    let mut fields = line.split_whitespace();
+
+    let timestamp = fields.next().unwrap_or_default();
+    let timestamp = OffsetDateTime::parse(timestamp, &Rfc3339)
+        .map_err(|_| format!("data non valida: {}", timestamp))?;
+
+    let direction = match fields.next() {
+        Some("TX") => Direction::Tx,
+        Some("RX") => Direction::Rx,
+        other => {
+            return Err(format!(
+                "direzione non valida: {}",
+                other.unwrap_or_default()
+            ))
+        }
+    };
+
+    let bytes = fields
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("byte non valido: {}", byte)))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Chunk {
+        timestamp,
+        direction,
+        bytes,
+    })
+}
+
+/// Why a frame of the capture could not be decoded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    BadCrc,
+    /// The length byte is shorter than any valid packet
+    ShortLength(usize),
+    /// The capture ends before the rest of the packet
+    Truncated(usize),
+    UnknownCode(u16),
+    /// Framed correctly but not a valid command or response
+    Malformed,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadCrc => write!(f, "CRC errato"),
+            Problem::ShortLength(len) => write!(f, "lunghezza troppo corta ({})", len),
+            Problem::Truncated(len) => write!(f, "pacchetto troncato, {} byte", len),
+            Problem::UnknownCode(code) => write!(f, "codice sconosciuto 0x{:04X}", code),
+            Problem::Malformed => write!(f, "pacchetto non valido"),
+        }
+    }
+}
+
+/// A frame of the capture with its meaning, shown on a single line
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub timestamp: OffsetDateTime,
+    pub direction: Direction,
+    pub raw: Vec<u8>,
+    /// Decoded content, empty when the frame could not be decoded
+    pub description: String,
+    pub problem: Option<Problem>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", time_of_day(self.timestamp), self.direction)?;
+        match &self.problem {
+            Some(problem) if self.description.is_empty() => write!(f, "!! {}", problem)?,
+            Some(problem) => write!(f, "{} !! {}", self.description, problem)?,
+            None => write!(f, "{}", self.description)?,
+        }
+
+        if !self.raw.is_empty() {
+            write!(f, " | {}", to_hex(&self.raw))?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of one direction, decoded on their own
+#[derive(Default)]
+struct Stream {
+    decoder: Decoder,
+    /// Bytes pushed so far, to place the frames in the stream
+    pushed: usize,
+    /// End of the last frame reported as corrupted
+    bad_until: usize,
+    /// An error was reported and no valid frame followed it yet
+    resyncing: bool,
+}
+
+impl Stream {
+    fn push(&mut self, bytes: &[u8]) {
+        self.decoder.push(bytes);
+        self.pushed += bytes.len();
+    }
+
+    /// Offset in the stream of the first byte still in the decoder.
+    fn position(&self) -> usize {
+        self.pushed - self.decoder.pending()
+    }
+
+    /// Whether an error for the bytes from `start` to `end` is a new problem:
+    /// while resyncing the decoder trips over the rest of the bad frame.
+    fn is_new_problem(&mut self, start: usize, end: usize) -> bool {
+        let new = !self.resyncing && start >= self.bad_until;
+        self.resyncing = true;
+        self.bad_until = self.bad_until.max(end);
+        new
+    }
+}
+
+/// Replays the captured bytes through the packet parsers.
+///
+/// After a corrupted frame only the first error is reported until the next
+/// valid frame, the decoder looks for a preamble inside the bytes it skips.
+pub fn transcript(chunks: &[Chunk]) -> Vec<Entry> {
+    let mut tx = Stream::default();
+    let mut rx = Stream::default();
+    let mut entries = Vec::new();
+    // Responses do not carry the code, take it from the last command
+    let mut last_command: Option<(Code, OffsetDateTime)> = None;
+
+    for chunk in chunks {
+        let stream = match chunk.direction {
+            Direction::Tx => &mut tx,
+            Direction::Rx => &mut rx,
+        };
+        stream.push(&chunk.bytes);
+
+        while let Some(result) = stream.decoder.try_next_frame() {
+            let entry = |raw: Vec<u8>, description: String, problem: Option<Problem>| Entry {
+                timestamp: chunk.timestamp,
+                direction: chunk.direction,
+                raw,
+                description,
+                problem,
+            };
+            // The decoder drops only the preamble of a frame it rejects
+            let start = stream.position().saturating_sub(1);
+
+            entries.push(match result {
+                Err(FrameError::BadCrc(raw)) => {
+                    if !stream.is_new_problem(start, start + raw.len()) {
+                        continue;
+                    }
+                    entry(raw, String::new(), Some(Problem::BadCrc))
+                }
+                Err(FrameError::BadLength(len)) => {
+                    if !stream.is_new_problem(start, start + 1) {
+                        continue;
+                    }
+                    entry(Vec::new(), String::new(), Some(Problem::ShortLength(len)))
+                }
+                Err(FrameError::Malformed(raw)) => {
+                    if !stream.is_new_problem(start, start + raw.len()) {
+                        continue;
+                    }
+                    entry(raw, String::new(), Some(Problem::Malformed))
+                }
+                Ok(raw) => {
+                    stream.resyncing = false;
+                    match chunk.direction {
+                        Direction::Tx => match Command::parse(&raw) {
+                            Ok(command) => {
+                                last_command = Some((command.code, chunk.timestamp));
+                                let problem = match command.code {
+                                    Code::Unknown(..) => {
+                                        Some(Problem::UnknownCode(u16::from_be_bytes([
+                                            raw[12], raw[13],
+                                        ])))
+                                    }
+                                    _ => None,
+                                };
+                                let description = format!(
+                                    "{:?} {:08X} -> {:08X} [{}]",
+                                    command.code,
+                                    u32::from_be_bytes(command.source),
+                                    u32::from_be_bytes(command.destination),
+                                    to_hex(command.payload())
+                                );
+                                entry(raw, description, problem)
+                            }
+                            Err(_) => entry(raw, String::new(), Some(Problem::Malformed)),
+                        },
+                        Direction::Rx => match Response::parse(&raw) {
+                            Ok(response) => {
+                                let mut description = format!(
+                                    "{} {:08X} -> {:08X} stato {:?} [{}]",
+                                    match last_command {
+                                        Some((code, _)) => format!("{:?}", code),
+                                        None => String::from("?"),
+                                    },
+                                    u32::from_be_bytes(response.source),
+                                    u32::from_be_bytes(response.destination),
+                                    response.status,
+                                    to_hex(response.payload())
+                                );
+                                if let Some((_, sent)) = last_command {
+                                    let round_trip = chunk.timestamp - sent;
+                                    description.push_str(&format!(
+                                        " {} ms",
+                                        round_trip.whole_milliseconds()
+                                    ));
+                                }
+                                entry(raw, description, None)
+                            }
+                            Err(_) => entry(raw, String::new(), Some(Problem::Malformed)),
+                        },
+                    }
+                }
+            });
+        }
+    }
+
+    // Whatever is left could not be completed before the end of the capture,
+    // unless it belongs to a frame already reported
+    for (stream, direction) in [(&tx, Direction::Tx), (&rx, Direction::Rx)] {
+        if stream.decoder.pending() > 0 && stream.position() >= stream.bad_until {
+            if let Some(last) = chunks.iter().rev().find(|c| c.direction == direction) {
+                entries.push(Entry {
+                    timestamp: last.timestamp,
+                    direction,
+                    raw: Vec::new(),
+                    description: String::new(),
+                    problem: Some(Problem::Truncated(stream.decoder.pending())),
+                });
+            }
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(code: Code, data: &[u8]) -> Vec<u8> {
+        let mut buffer = [0; 256];
+        let len = Command::new(code, [0; 4], [0x14, 3, 1, 0], data)
+            .serialize(&mut buffer)
+            .unwrap();
+        buffer[0..len].to_vec()
+    }
+
+    fn response(data: &[u8]) -> Vec<u8> {
+        let mut buffer = [0; 256];
+        let len = Response::ok([0; 4], [0x14, 3, 1, 0], data)
+            .serialize(&mut buffer)
+            .unwrap();
+        buffer[0..len].to_vec()
+    }
+
+    fn chunk(direction: Direction, bytes: Vec<u8>) -> Chunk {
+        Chunk {
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            direction,
+            bytes,
+        }
+    }
+
+    fn problems(chunks: &[Chunk]) -> Vec<Problem> {
+        transcript(chunks)
+            .into_iter()
+            .filter_map(|entry| entry.problem)
+            .collect()
+    }
+
+    fn capture_file(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.txt", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_written_capture() {
+        let path = std::env::temp_dir().join(format!("cattura-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut writer = Writer::open(&path).unwrap();
+        writer.write(Direction::Tx, &[0x02, 0x01]).unwrap();
+        writer.write(Direction::Rx, &[0xFF]).unwrap();
+        drop(writer);
+
+        let chunks = load(&path).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].direction, Direction::Tx);
+        assert_eq!(chunks[0].bytes, [0x02, 0x01]);
+        assert_eq!(chunks[1].direction, Direction::Rx);
+        assert_eq!(chunks[1].bytes, [0xFF]);
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let path = capture_file("cattura-header", "2026-10-17T10:00:00Z TX 02\n");
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn load_reports_the_bad_line() {
+        let path = capture_file(
+            "cattura-line",
+            &format!(
+                "{}\n2026-10-17T10:00:00Z TX 02\n\n2026-10-17T10:00:00Z XX 02\n",
+                HEADER
+            ),
+        );
+        let error = load(&path).unwrap_err();
+        assert!(error.ends_with(":4: direzione non valida: XX"), "{}", error);
+    }
+
+    #[test]
+    fn valid_exchange() {
+        let entries = transcript(&[
+            chunk(Direction::Tx, command(Code::ReadAddress, &[])),
+            chunk(Direction::Rx, response(&[0x14, 3, 1, 0])),
+        ]);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.problem.is_none()));
+        assert!(entries[1].description.starts_with("ReadAddress"));
+    }
+
+    #[test]
+    fn bad_crc_reported_once() {
+        // A 0x02 inside the corrupted frame must not add problems of its own
+        let bytes = vec![
+            0x02, 0x01, 0x10, 0, 0, 0, 0, 0, 0x14, 0x03, 0x02, 0x02, 0x00, 0x02, 0x01, 0xCE,
+        ];
+        assert_eq!(problems(&[chunk(Direction::Rx, bytes)]), [Problem::BadCrc]);
+    }
+
+    #[test]
+    fn bad_crc_then_valid_frame() {
+        let mut bytes = response(&[0x55]);
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        bytes.extend(response(&[0x55]));
+
+        let entries = transcript(&[chunk(Direction::Rx, bytes)]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].problem, Some(Problem::BadCrc));
+        assert_eq!(entries[1].problem, None);
+    }
+
+    #[test]
+    fn short_length() {
+        let mut bytes = vec![0x02, 0x01, 0x05];
+        bytes.extend(response(&[]));
+        assert_eq!(
+            problems(&[chunk(Direction::Rx, bytes)]),
+            [Problem::ShortLength(5)]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = response(&[0x55]);
+        assert_eq!(
+            problems(&[chunk(Direction::Rx, bytes[0..6].to_vec())]),
+            [Problem::Truncated(6)]
+        );
+    }
+
+    #[test]
+    fn unknown_code() {
+        assert_eq!(
+            problems(&[chunk(
+                Direction::Tx,
+                command(Code::Unknown(0x12, 0x34), &[])
+            )]),
+            [Problem::UnknownCode(0x1234)]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        // Framed with a valid CRC but with an unknown packet type
+        let mut bytes = response(&[]);
+        bytes[1] = 0x05;
+        let len = bytes.len();
+        bytes[len - 1] = crate::controller::mblp::crc(&bytes[0..len - 1]);
+        assert_eq!(
+            problems(&[chunk(Direction::Rx, bytes)]),
+            [Problem::Malformed]
+        );
+    }
+}
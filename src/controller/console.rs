@@ -0,0 +1,143 @@
+// Raw commands typed by the engineers, for codes the GUI has no action for
+
+use super::device::Port;
+use super::error::Error;
+use super::json;
+use super::mblp::{self, Code, Response, HEADER_LENGTH};
+use super::serial;
+use super::traffic::to_hex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Saved favorites, loaded at start
+pub const FAVORITES_FILE: &str = "preferiti.json";
+
+/// Longest payload that fits the length byte of a command
+pub const MAX_PAYLOAD: usize = 255 - HEADER_LENGTH;
+
+/// Oldest requests are dropped from the history beyond this count
+const MAX_HISTORY: usize = 50;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RawRequest {
+    pub destination: u32,
+    pub code: u16,
+    pub payload: Vec<u8>,
+    /// Data bytes the response must carry, any when missing
+    #[serde(default)]
+    pub expected_len: Option<usize>,
+    /// Sent as a probe, which is told by the type byte and not by `code`
+    #[serde(default)]
+    pub probe: bool,
+}
+
+impl RawRequest {
+    pub fn code(&self) -> Code {
+        if self.probe {
+            Code::ProbeId
+        } else {
+            Code::from(self.code)
+        }
+    }
+}
+
+impl fmt::Display for RawRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.probe {
+            write!(f, "probe_id")?;
+        } else {
+            write!(f, "0x{:04X}", self.code)?;
+        }
+        write!(f, " a {:08X} [{}]", self.destination, to_hex(&self.payload))?;
+        if let Some(len) = self.expected_len {
+            write!(f, " attesi {} byte", len)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Favorite {
+    pub name: String,
+    pub request: RawRequest,
+}
+
+/// A request sent from the console and what came back
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub request: RawRequest,
+    /// The decoded response, or why there is none
+    pub result: Result<String, String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Console {
+    pub history: Vec<HistoryEntry>,
+    pub favorites: Vec<Favorite>,
+}
+
+impl Console {
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.history.len() >= MAX_HISTORY {
+            self.history.remove(0);
+        }
+        self.history.push(entry);
+    }
+}
+
+/// Sends the request and checks the length of the response data.
+pub fn send(port: &mut Port, request: &RawRequest) -> Result<Response, Error> {
+    let code = request.code();
+    let destination = request.destination.to_be_bytes();
+    let response = serial::send_command(port, code, destination, &request.payload)?;
+
+    match request.expected_len {
+        Some(len) if len != response.data_len as usize => Err(Error::UnexpectedPayload {
+            code,
+            destination,
+            data: response.payload().to_vec(),
+        }),
+        _ => Ok(response),
+    }
+}
+
+/// The response followed by its meaning, when the command is registered.
+pub fn describe(request: &RawRequest, response: &Response) -> String {
+    let data = response.payload();
+    match mblp::spec(request.code()).and_then(|spec| (spec.decode)(data)) {
+        Some(meaning) if !meaning.is_empty() => format!("{}\t{}", response, meaning),
+        _ => response.to_string(),
+    }
+}
+
+pub fn load_favorites() -> Vec<Favorite> {
+    json::load_or_default(Path::new(FAVORITES_FILE)).unwrap_or_else(|e| {
+        log::error!("Cannot load favorites: {}", e);
+        Vec::new()
+    })
+}
+
+pub fn save_favorites(favorites: &[Favorite]) -> Result<(), String> {
+    let text = serde_json::to_string_pretty(favorites).map_err(|e| e.to_string())?;
+    fs::write(FAVORITES_FILE, text).map_err(|e| format!("{}: {}", FAVORITES_FILE, e))
+}
+
+/// Parses bytes written in hex, optionally separated by spaces, e.g. "01 0A FF" or "010AFF".
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text.split_whitespace().collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Numero dispari di cifre: {}", text));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            digits
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("Byte non valido: {}", text))
+        })
+        .collect()
+}
@@ -0,0 +1,624 @@
+// Operations on a single board, shared by the GUI controller and the command line
+
+use super::error::Error;
+use super::job::{Observer, Progress};
+use super::mblp::{
+    Code, CpuId, DeviceAddress, FwVersion, InputState, OutputCommand, Payload, Response,
+};
+use super::plan::{Action, PlanStep, TestPlan};
+use super::serial;
+use crate::model::DeviceInfo;
+use serde::Serialize;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type Port = serial::Link;
+
+pub fn read_firmware_version(port: &mut Port, destination: [u8; 4]) -> Result<FwVersion, Error> {
+    let address = DeviceAddress(u32::from_be_bytes(destination)).encode();
+    let resp = serial::send(port, "read_fw_version", destination, &address)?;
+    decode(Code::ReadFWVersion, destination, &resp)
+}
+
+pub fn read_serial_number(port: &mut Port, destination: [u8; 4]) -> Result<u32, Error> {
+    let address = DeviceAddress(u32::from_be_bytes(destination)).encode();
+    let resp = serial::send(port, "read_address", destination, &address)?;
+    decode(Code::ReadAddress, destination, &resp).map(|DeviceAddress(address)| address)
+}
+
+/// Reads the unique ID of the microcontroller, which never changes with the address.
+pub fn read_cpu_id(port: &mut Port, destination: [u8; 4]) -> Result<CpuId, Error> {
+    let resp = serial::send(port, "probe_id", destination, &[])?;
+    decode(Code::ProbeId, destination, &resp)
+}
+
+/// Read-back attempts after writing a new address
+const VERIFY_ATTEMPTS: u32 = 3;
+const VERIFY_DELAY: Duration = Duration::from_millis(50);
+
+/// Writes the address and reads it back from the board to confirm it was taken.
+pub fn set_serial_number(port: &mut Port, destination: [u8; 4]) -> Result<(), Error> {
+    let address = DeviceAddress(u32::from_be_bytes(destination)).encode();
+    serial::send(port, "set_address", destination, &address)?;
+
+    let expected = u32::from_be_bytes(destination);
+    let mut read = None;
+
+    for attempt in 1..=VERIFY_ATTEMPTS {
+        thread::sleep(VERIFY_DELAY);
+        match read_serial_number(port, destination) {
+            Ok(address) if address == expected => return Ok(()),
+            Ok(address) => {
+                log::warn!("Verify attempt {}: read {:08X}", attempt, address);
+                read = Some(address);
+            }
+            Err(e) => log::warn!("Verify attempt {}: {}", attempt, e),
+        }
+    }
+
+    Err(Error::NotVerified { destination, read })
+}
+
+/// Probes every address in turn and returns the boards that answered,
+/// up to the address where the scan was cancelled.
+pub fn scan(port: &mut Port, addresses: &[u32], observer: &mut dyn Observer) -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+
+    // Most addresses are expected to stay silent, retrying them only slows the scan
+    let retries = std::mem::replace(&mut port.settings.retries, 0);
+
+    for (index, &address) in addresses.iter().enumerate() {
+        if observer.is_cancelled() {
+            break;
+        }
+        observer.progress(Progress::at(
+            index,
+            addresses.len(),
+            format!("Indirizzo {:08X}", address),
+            None,
+        ));
+
+        let destination = address.to_be_bytes();
+        match read_serial_number(port, destination) {
+            Ok(address) => devices.push(DeviceInfo {
+                address,
+                version: read_firmware_version(port, destination).ok(),
+            }),
+            Err(Error::Timeout { .. }) => (),
+            Err(e) => log::warn!("Scan of {:08X}: {}", address, e),
+        }
+    }
+
+    port.settings.retries = retries;
+
+    devices
+}
+
+pub(crate) fn decode<P: Payload>(
+    code: Code,
+    destination: [u8; 4],
+    resp: &Response,
+) -> Result<P, Error> {
+    P::decode(resp.payload()).map_err(|_| Error::UnexpectedPayload {
+        code,
+        destination,
+        data: resp.payload().to_vec(),
+    })
+}
+
+pub(crate) fn set_output(
+    port: &mut Port,
+    destination: [u8; 4],
+    relay: u8,
+    on: bool,
+) -> Result<(), Error> {
+    let command = OutputCommand { relay, on };
+    serial::send(port, "set_output", destination, &command.encode()).map(|_| ())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayResult {
+    Untested,
+    Pass,
+    Fail,
+}
+
+#[derive(Clone, Debug)]
+pub struct TestFailure {
+    pub step: String,
+    pub error: Error,
+}
+
+impl fmt::Display for TestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.step, self.error)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StepStatus {
+    Pending,
+    Running,
+    Pass,
+    Fail,
+}
+
+/// Inputs a step expects and those last read from the board
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InputCheck {
+    pub expected: u8,
+    pub mask: u8,
+    pub actual: Option<u8>,
+}
+
+/// A step of the plan as it was run
+#[derive(Clone, Debug)]
+pub struct StepResult {
+    pub description: String,
+    pub status: StepStatus,
+    /// Present for the steps that check the inputs
+    pub inputs: Option<InputCheck>,
+    pub duration: Option<Duration>,
+    pub error: Option<Error>,
+}
+
+impl StepResult {
+    pub fn pending(step: &PlanStep) -> Self {
+        StepResult {
+            description: step.description(),
+            status: StepStatus::Pending,
+            inputs: match step.action {
+                Action::ExpectInputs { expected, mask, .. } => Some(InputCheck {
+                    expected,
+                    mask,
+                    actual: None,
+                }),
+                _ => None,
+            },
+            duration: None,
+            error: None,
+        }
+    }
+}
+
+/// An input check that failed, as seen from the relays that were on
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RelayFault {
+    /// `None` when every relay was off
+    pub relay: Option<u8>,
+    pub step: String,
+    pub expected: u8,
+    pub actual: u8,
+    /// Expected bits that stayed low
+    pub missing: u8,
+    /// Bits raised beyond the expected ones, cross-talk when a relay is on
+    pub extra: u8,
+}
+
+impl fmt::Display for RelayFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.relay {
+            Some(relay) => write!(f, "rele {}: ", relay)?,
+            None => write!(f, "rele spenti: ")?,
+        }
+        write!(
+            f,
+            "ingressi 0x{:02X} invece di 0x{:02X}",
+            self.actual, self.expected
+        )?;
+        if self.missing != 0 {
+            write!(f, ", non attivi i bit {}", bits(self.missing))?;
+        }
+        match (self.extra, self.relay) {
+            (0, _) => Ok(()),
+            (extra, Some(_)) => write!(f, ", attiva anche i bit {} (diafonia)", bits(extra)),
+            (extra, None) => write!(f, ", attivi i bit {}", bits(extra)),
+        }
+    }
+}
+
+/// Numbers of the bits set in `mask`, e.g. "1, 2".
+fn bits(mask: u8) -> String {
+    (0..8)
+        .filter(|bit| mask & (1 << bit) != 0)
+        .map(|bit| bit.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Blames the mismatch of an input check on every relay that was on.
+fn diagnose(
+    step: String,
+    expected: u8,
+    mask: u8,
+    actual: u8,
+    energized: &[bool],
+) -> Vec<RelayFault> {
+    let fault = |relay| RelayFault {
+        relay,
+        step: step.clone(),
+        expected,
+        actual,
+        missing: expected & mask & !actual,
+        extra: actual & mask & !expected,
+    };
+
+    let faults: Vec<_> = (0..energized.len() as u8)
+        .filter(|&relay| energized[relay as usize])
+        .map(|relay| fault(Some(relay)))
+        .collect();
+    if faults.is_empty() {
+        vec![fault(None)]
+    } else {
+        faults
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TestOutcome {
+    pub relays: Vec<RelayResult>,
+    /// One for each step of the plan, those after a failure stay pending
+    /// unless the plan runs all the steps
+    pub steps: Vec<StepResult>,
+    /// Every step that failed, in order
+    pub failures: Vec<TestFailure>,
+    pub faults: Vec<RelayFault>,
+    /// Whether the relays were switched off at the end, `None` if they were
+    /// left as the plan did
+    pub safe_state: Option<Result<(), Error>>,
+}
+
+impl TestOutcome {
+    /// Nothing run yet.
+    pub fn new(plan: &TestPlan) -> Self {
+        TestOutcome {
+            relays: vec![RelayResult::Untested; plan.relays as usize],
+            steps: plan.steps.iter().map(StepResult::pending).collect(),
+            failures: Vec::new(),
+            faults: Vec::new(),
+            safe_state: None,
+        }
+    }
+
+    /// The first failure, if any.
+    pub fn result(&self) -> Result<(), TestFailure> {
+        match self.failures.first() {
+            Some(failure) => Err(failure.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Switches off every relay of the board, trying all of them even if some fail.
+fn switch_all_off(port: &mut Port, destination: [u8; 4], relays: u8) -> Result<(), Error> {
+    let mut result = Ok(());
+    for relay in 0..relays {
+        if let Err(e) = set_output(port, destination, relay, false) {
+            log::error!("Relay {} not switched off: {}", relay, e);
+            result = Err(e);
+        }
+    }
+    result
+}
+
+/// Switches every relay off and logs whether the board confirmed it.
+pub fn safe_state(port: &mut Port, destination: [u8; 4], relays: u8) -> Result<(), Error> {
+    let address = u32::from_be_bytes(destination);
+    let result = switch_all_off(port, destination, relays);
+    match &result {
+        Ok(()) => log::info!("Relays of {:08X} switched off", address),
+        Err(e) => log::error!("Relays of {:08X} may still be on: {}", address, e),
+    }
+    result
+}
+
+/// Puts the board in its safe state when dropped, so that no way out of a
+/// test, a panic included, leaves a relay energized.
+struct SafeState<'a> {
+    port: &'a mut Port,
+    destination: [u8; 4],
+    relays: u8,
+    armed: bool,
+}
+
+impl<'a> SafeState<'a> {
+    fn new(port: &'a mut Port, destination: [u8; 4], relays: u8) -> Self {
+        SafeState {
+            port,
+            destination,
+            relays,
+            armed: true,
+        }
+    }
+
+    /// Switches the relays off now, to learn whether it worked.
+    fn release(mut self) -> Result<(), Error> {
+        self.armed = false;
+        safe_state(self.port, self.destination, self.relays)
+    }
+
+    /// Leaves the relays as they are.
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for SafeState<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            safe_state(self.port, self.destination, self.relays).ok();
+        }
+    }
+}
+
+/// Runs the steps of `plan`, reporting each one to the observer as it starts
+/// and ends, until one fails or the observer cancels the test.
+/// With `plan.run_all` a failure does not stop the test.
+///
+/// Every relay is switched off when a step fails, when the test is cancelled
+/// and at the end of a `run_all` test; a passed test leaves them as the plan does.
+pub fn test_device(
+    port: &mut Port,
+    destination: [u8; 4],
+    plan: &TestPlan,
+    observer: &mut dyn Observer,
+) -> TestOutcome {
+    let guard = SafeState::new(port, destination, plan.relays);
+    let mut outcome = run_plan(guard.port, destination, plan, observer);
+
+    if plan.run_all || !outcome.failures.is_empty() {
+        outcome.safe_state = Some(guard.release());
+    } else {
+        guard.disarm();
+    }
+
+    outcome
+}
+
+fn run_plan(
+    port: &mut Port,
+    destination: [u8; 4],
+    plan: &TestPlan,
+    observer: &mut dyn Observer,
+) -> TestOutcome {
+    let mut outcome = TestOutcome::new(plan);
+    let mut energized = vec![false; plan.relays as usize];
+
+    for (index, step) in plan.steps.iter().enumerate() {
+        if observer.is_cancelled() {
+            outcome.failures.push(TestFailure {
+                step: step.description(),
+                error: Error::Cancelled,
+            });
+            return outcome;
+        }
+
+        let relay = match step.action {
+            Action::SetOutput { relay, .. } => Some(relay),
+            _ => None,
+        };
+        observer.progress(Progress::at(
+            index,
+            plan.steps.len(),
+            step.description(),
+            relay,
+        ));
+        outcome.steps[index].status = StepStatus::Running;
+        observer.step(index, &outcome.steps[index]);
+
+        let start = Instant::now();
+        let result = run_step(port, destination, &step.action, &mut energized);
+
+        let step_result = &mut outcome.steps[index];
+        step_result.duration = Some(start.elapsed());
+        if let Some(inputs) = step_result.inputs.as_mut() {
+            inputs.actual = match &result {
+                Ok(actual) => *actual,
+                Err(Error::InputMismatch { actual, .. }) => Some(*actual),
+                Err(_) => None,
+            };
+        }
+        step_result.status = match result {
+            Ok(_) => StepStatus::Pass,
+            Err(_) => StepStatus::Fail,
+        };
+        step_result.error = result.as_ref().err().cloned();
+        observer.step(index, step_result);
+
+        match result {
+            Ok(_) => {
+                // A successful check validates every relay that is on
+                if let Action::ExpectInputs { .. } = step.action {
+                    for (result, &on) in outcome.relays.iter_mut().zip(&energized) {
+                        if on && *result == RelayResult::Untested {
+                            *result = RelayResult::Pass;
+                        }
+                    }
+                }
+            }
+            Err(error) => {
+                match step.action {
+                    Action::SetOutput { relay, .. } => {
+                        outcome.relays[relay as usize] = RelayResult::Fail
+                    }
+                    Action::ExpectInputs { .. } => {
+                        for (result, &on) in outcome.relays.iter_mut().zip(&energized) {
+                            if on {
+                                *result = RelayResult::Fail;
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+
+                if let Error::InputMismatch {
+                    expected,
+                    mask,
+                    actual,
+                    ..
+                } = error
+                {
+                    outcome.faults.extend(diagnose(
+                        step.description(),
+                        expected,
+                        mask,
+                        actual,
+                        &energized,
+                    ));
+                }
+                outcome.failures.push(TestFailure {
+                    step: step.description(),
+                    error,
+                });
+                if !plan.run_all {
+                    break;
+                }
+            }
+        }
+    }
+
+    outcome
+}
+
+fn run_step(
+    port: &mut Port,
+    destination: [u8; 4],
+    action: &Action,
+    energized: &mut [bool],
+) -> Result<Option<u8>, Error> {
+    match *action {
+        Action::SetOutput { relay, on } => {
+            set_output(port, destination, relay, on)?;
+            energized[relay as usize] = on;
+        }
+
+        Action::AllOff => {
+            for (relay, on) in energized.iter_mut().enumerate() {
+                set_output(port, destination, relay as u8, false)?;
+                *on = false;
+            }
+        }
+
+        Action::Wait { ms } => thread::sleep(Duration::from_millis(ms)),
+
+        Action::ExpectInputs {
+            expected,
+            mask,
+            tolerance_ms,
+        } => {
+            let start = Instant::now();
+            loop {
+                let response = serial::send(port, "read_input", destination, &[])?;
+                let InputState(actual) = decode(Code::ReadInput, destination, &response)?;
+                if actual & mask == expected & mask {
+                    return Ok(Some(actual));
+                }
+
+                if start.elapsed() >= Duration::from_millis(tolerance_ms) {
+                    return Err(Error::InputMismatch {
+                        destination,
+                        expected,
+                        mask,
+                        actual,
+                    });
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        Action::ExpectFirmware { min, max } => {
+            let found = read_firmware_version(port, destination)?;
+            if found < min || found > max {
+                return Err(Error::FirmwareMismatch { found, min, max });
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Case {
+        name: &'static str,
+        expected: u8,
+        mask: u8,
+        actual: u8,
+        energized: &'static [bool],
+        /// Relay blamed, missing and extra bits of every fault
+        faults: &'static [(Option<u8>, u8, u8)],
+    }
+
+    const CASES: [Case; 5] = [
+        Case {
+            name: "input high with every relay off",
+            expected: 0x00,
+            mask: 0xFF,
+            actual: 0x04,
+            energized: &[false; 4],
+            faults: &[(None, 0x00, 0x04)],
+        },
+        Case {
+            name: "stuck relay",
+            expected: 0x02,
+            mask: 0xFF,
+            actual: 0x00,
+            energized: &[false, true, false, false],
+            faults: &[(Some(1), 0x02, 0x00)],
+        },
+        Case {
+            name: "relay raising an extra bit",
+            expected: 0x04,
+            mask: 0xFF,
+            actual: 0x0C,
+            energized: &[false, false, true, false],
+            faults: &[(Some(2), 0x00, 0x08)],
+        },
+        Case {
+            name: "masked bits are ignored",
+            expected: 0x01,
+            mask: 0x0F,
+            actual: 0xF0,
+            energized: &[true, false, false, false],
+            faults: &[(Some(0), 0x01, 0x00)],
+        },
+        Case {
+            name: "every relay on is blamed",
+            expected: 0x03,
+            mask: 0xFF,
+            actual: 0x01,
+            energized: &[true, true, false, false],
+            faults: &[(Some(0), 0x02, 0x00), (Some(1), 0x02, 0x00)],
+        },
+    ];
+
+    #[test]
+    fn diagnose_table() {
+        for case in CASES {
+            let found = diagnose(
+                case.name.to_string(),
+                case.expected,
+                case.mask,
+                case.actual,
+                case.energized,
+            );
+            let wanted: Vec<_> = case
+                .faults
+                .iter()
+                .map(|&(relay, missing, extra)| RelayFault {
+                    relay,
+                    step: case.name.to_string(),
+                    expected: case.expected,
+                    actual: case.actual,
+                    missing,
+                    extra,
+                })
+                .collect();
+            assert_eq!(found, wanted, "{}", case.name);
+        }
+    }
+}
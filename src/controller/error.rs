@@ -0,0 +1,196 @@
+use super::mblp::{Code, FwVersion, ResponseStatus};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The serial port could not be read or written
+    Port(String),
+    /// No complete response arrived in time
+    Timeout {
+        code: Code,
+        destination: [u8; 4],
+    },
+    /// A response arrived but its checksum did not match
+    BadCrc {
+        code: Code,
+        destination: [u8; 4],
+    },
+    /// Only part of a response arrived, or its length byte was invalid
+    BadLength {
+        code: Code,
+        destination: [u8; 4],
+        len: usize,
+    },
+    /// The device answered with a status other than ok, like a NACK
+    Device {
+        code: Code,
+        destination: [u8; 4],
+        status: ResponseStatus,
+    },
+    /// The response is well formed but its content is not what was asked for
+    UnexpectedPayload {
+        code: Code,
+        destination: [u8; 4],
+        data: Vec<u8>,
+    },
+    /// The inputs read back do not match the expected ones
+    InputMismatch {
+        destination: [u8; 4],
+        expected: u8,
+        mask: u8,
+        actual: u8,
+    },
+    /// The firmware version is outside of the accepted range
+    FirmwareMismatch {
+        found: FwVersion,
+        min: FwVersion,
+        max: FwVersion,
+    },
+    /// The address was written but reading it back did not confirm it
+    NotVerified {
+        destination: [u8; 4],
+        read: Option<u32>,
+    },
+    /// The command is not registered or its data does not match the registry
+    InvalidCommand(String),
+    /// The operator stopped the operation
+    Cancelled,
+    /// The port is taken by a job still running
+    Busy,
+    NotConnected,
+}
+
+impl Error {
+    /// Short identifier of the variant, stable for logs and scripts.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Port(_) => "port",
+            Error::Timeout { .. } => "timeout",
+            Error::BadCrc { .. } => "bad_crc",
+            Error::BadLength { .. } => "bad_length",
+            Error::Device { .. } => "device",
+            Error::UnexpectedPayload { .. } => "unexpected_payload",
+            Error::InputMismatch { .. } => "input_mismatch",
+            Error::FirmwareMismatch { .. } => "firmware_mismatch",
+            Error::NotVerified { .. } => "not_verified",
+            Error::InvalidCommand(_) => "invalid_command",
+            Error::Cancelled => "cancelled",
+            Error::Busy => "busy",
+            Error::NotConnected => "not_connected",
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Port(e.to_string())
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Port(e.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = |destination: &[u8; 4]| u32::from_be_bytes(*destination);
+
+        match self {
+            Error::Port(e) => write!(f, "Errore sulla porta: {}", e),
+            Error::Timeout { code, destination } => {
+                write!(f, "Timeout! ({:?} a {:08X})", code, target(destination))
+            }
+            Error::BadCrc { code, destination } => {
+                write!(
+                    f,
+                    "CRC non valido ({:?} a {:08X})",
+                    code,
+                    target(destination)
+                )
+            }
+            Error::BadLength {
+                code,
+                destination,
+                len,
+            } => write!(
+                f,
+                "Risposta incompleta, {} byte ({:?} a {:08X})",
+                len,
+                code,
+                target(destination)
+            ),
+            Error::Device {
+                code,
+                destination,
+                status,
+            } => write!(
+                f,
+                "Il dispositivo ha segnalato un errore, stato {:?} ({:?} a {:08X})",
+                status,
+                code,
+                target(destination)
+            ),
+            Error::UnexpectedPayload {
+                code,
+                destination,
+                data,
+            } => write!(
+                f,
+                "Risposta non valida {:02X?} ({:?} a {:08X})",
+                data,
+                code,
+                target(destination)
+            ),
+            Error::InputMismatch {
+                expected,
+                mask: 0xFF,
+                actual,
+                ..
+            } => write!(
+                f,
+                "Ingressi non validi: mi aspettavo 0x{:02X}, e' arrivato 0x{:02X}",
+                expected, actual
+            ),
+            Error::InputMismatch {
+                expected,
+                mask,
+                actual,
+                ..
+            } => write!(
+                f,
+                "Ingressi non validi: mi aspettavo 0x{:02X} (maschera 0x{:02X}), e' arrivato 0x{:02X}",
+                expected, mask, actual
+            ),
+            Error::FirmwareMismatch { found, min, max } => write!(
+                f,
+                "Firmware {} fuori dall'intervallo {} - {}",
+                found, min, max
+            ),
+            Error::NotVerified {
+                destination,
+                read: Some(read),
+            } => write!(
+                f,
+                "Matricola 0x{:08X} scritta ma non verificata, letta 0x{:08X}",
+                target(destination),
+                read
+            ),
+            Error::NotVerified {
+                destination,
+                read: None,
+            } => write!(
+                f,
+                "Matricola 0x{:08X} scritta ma non verificata, nessuna risposta",
+                target(destination)
+            ),
+            Error::InvalidCommand(e) => write!(f, "Comando non valido: {}", e),
+            Error::Cancelled => write!(f, "Operazione annullata"),
+            Error::Busy => write!(f, "Un'altra operazione e' in corso"),
+            Error::NotConnected => write!(f, "Nessuna porta connessa!"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,159 @@
+// Long operations run on a thread of their own, so the controller keeps
+// processing messages and the operator can stop them
+//
+// A job owns the link while it runs and hands it back when it ends.
+
+use super::device::StepResult;
+use super::serial::Link;
+use crate::model::Model;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// How far an operation has gone
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    pub step: String,
+    /// Relay the current step acts on, if any
+    pub relay: Option<u8>,
+    pub percent: u8,
+}
+
+impl Progress {
+    /// Progress at the start of step `index` out of `count`.
+    pub fn at(index: usize, count: usize, step: String, relay: Option<u8>) -> Self {
+        Progress {
+            step,
+            relay,
+            percent: (index * 100).checked_div(count).unwrap_or(100) as u8,
+        }
+    }
+}
+
+/// Follows a long operation and tells it when to stop.
+pub trait Observer {
+    fn progress(&mut self, progress: Progress);
+
+    /// A step of a test started or ended.
+    fn step(&mut self, _index: usize, _result: &StepResult) {}
+
+    /// Checked between steps, the operation stops when it returns true.
+    fn is_cancelled(&self) -> bool;
+}
+
+/// For the command line, where operations run to the end unobserved
+pub struct Unobserved;
+
+impl Observer for Unobserved {
+    fn progress(&mut self, _progress: Progress) {}
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// The job shown by the view
+#[derive(Clone, Debug, Default)]
+pub struct JobState {
+    pub name: String,
+    pub progress: Progress,
+    /// Stop was requested, the job ends at the next step
+    pub stopping: bool,
+}
+
+/// Given to the operation to report to the model.
+pub struct Reporter {
+    model: Arc<Mutex<Model>>,
+    ctx: egui::Context,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Reporter {
+    pub fn modify_model<F>(&self, mut op: F)
+    where
+        F: FnMut(&mut Model),
+    {
+        let mut model = self.model.lock().unwrap();
+        op(&mut model);
+        self.ctx.request_repaint();
+    }
+
+    pub fn notify(&self, msg: String) {
+        self.modify_model(|m| m.message(msg.clone()))
+    }
+}
+
+impl Observer for Reporter {
+    fn progress(&mut self, progress: Progress) {
+        self.modify_model(|m| {
+            if let Some(job) = m.job.as_mut() {
+                job.progress = progress.clone();
+            }
+        });
+    }
+
+    fn step(&mut self, index: usize, result: &StepResult) {
+        self.modify_model(|m| {
+            if let Some(step) = m.test_run.as_mut().and_then(|run| run.steps.get_mut(index)) {
+                *step = result.clone();
+            }
+        });
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Job {
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<Link>,
+}
+
+impl Job {
+    /// Runs `op` on `link` from a new thread and shows the job in the model
+    /// until it is joined.
+    pub fn spawn<F>(
+        name: String,
+        mut link: Link,
+        model: Arc<Mutex<Model>>,
+        ctx: egui::Context,
+        op: F,
+    ) -> Self
+    where
+        F: FnOnce(&mut Link, &mut Reporter) + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut reporter = Reporter {
+            model,
+            ctx,
+            cancel: Arc::clone(&cancel),
+        };
+        reporter.modify_model(|m| {
+            m.job = Some(JobState {
+                name: name.clone(),
+                ..JobState::default()
+            })
+        });
+
+        let handle = thread::spawn(move || {
+            op(&mut link, &mut reporter);
+            link
+        });
+
+        Job { cancel, handle }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Waits for the job to end and takes the link back, unless the job panicked.
+    pub fn join(self) -> Option<Link> {
+        self.handle.join().ok()
+    }
+}
@@ -0,0 +1,20 @@
+// Settings and records kept as JSON files next to the executable
+
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+
+/// Reads and parses `path`, errors name the file.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Like `load`, with the default value when the file does not exist.
+pub fn load_or_default<T: DeserializeOwned + Default>(path: &Path) -> Result<T, String> {
+    if path.exists() {
+        load(path)
+    } else {
+        Ok(T::default())
+    }
+}
@@ -0,0 +1,98 @@
+// Manual control of the relays and live view of the inputs, for rework and
+// debugging of a single board
+
+use super::device::{self, Port};
+use super::error::Error;
+use super::mblp::{Code, InputState};
+use super::serial;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_POLL_MS: u64 = 200;
+/// Faster polling would keep the line always busy
+pub const MIN_POLL_MS: u64 = 50;
+
+/// Outcome of the last command sent on a channel
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChannelStatus {
+    #[default]
+    Idle,
+    /// Answered after this round trip
+    Ok(Duration),
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Relay {
+    /// Last state acknowledged by the board
+    pub on: bool,
+    pub status: ChannelStatus,
+}
+
+/// What the manual panel shows
+#[derive(Clone, Debug, Default)]
+pub struct Manual {
+    pub address: u32,
+    pub relays: Vec<Relay>,
+    /// Last inputs read, `None` until the first answer
+    pub inputs: Option<InputState>,
+    pub input_status: ChannelStatus,
+}
+
+impl Manual {
+    /// All relays off and nothing read yet.
+    pub fn new(address: u32, relays: u8) -> Self {
+        Manual {
+            address,
+            relays: vec![Relay::default(); relays as usize],
+            inputs: None,
+            input_status: ChannelStatus::Idle,
+        }
+    }
+
+    pub fn any_on(&self) -> bool {
+        self.relays.iter().any(|relay| relay.on)
+    }
+}
+
+/// Inputs read periodically while the panel is open
+#[derive(Clone, Debug)]
+pub struct Poll {
+    pub address: u32,
+    pub period: Duration,
+    pub next: Instant,
+}
+
+impl Poll {
+    pub fn new(address: u32, period_ms: u64) -> Self {
+        Poll {
+            address,
+            period: Duration::from_millis(period_ms.max(MIN_POLL_MS)),
+            next: Instant::now(),
+        }
+    }
+}
+
+/// Switches a relay and returns the round trip of the command.
+pub fn set_relay(port: &mut Port, address: u32, relay: u8, on: bool) -> Result<Duration, Error> {
+    let start = Instant::now();
+    device::set_output(port, address.to_be_bytes(), relay, on)?;
+    Ok(start.elapsed())
+}
+
+/// Reads the inputs and returns them with the round trip of the command.
+pub fn read_inputs(port: &mut Port, address: u32) -> Result<(InputState, Duration), Error> {
+    let destination = address.to_be_bytes();
+    let start = Instant::now();
+    let response = serial::send(port, "read_input", destination, &[])?;
+    let inputs = device::decode(Code::ReadInput, destination, &response)?;
+    Ok((inputs, start.elapsed()))
+}
+
+impl From<Result<Duration, Error>> for ChannelStatus {
+    fn from(result: Result<Duration, Error>) -> Self {
+        match result {
+            Ok(round_trip) => ChannelStatus::Ok(round_trip),
+            Err(e) => ChannelStatus::Failed(e.to_string()),
+        }
+    }
+}
@@ -7,14 +7,6 @@ pub const PREAMBLE: u8 = 0x02;
 pub const HEADER_LENGTH: usize = 15;
 pub const RESPONSE_HEADER_LENGTH: usize = 14;
 
-const CODE_U16: [(u16, Code); 5] = [
-    (0x0101, Code::ReadInput),
-    (0xFF01, Code::SetOutput),
-    (0xFF03, Code::SetAddress),
-    (0xFF04, Code::ReadAddress),
-    (0x400A, Code::ReadFWVersion),
-];
-
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Code {
     ReadInput,
@@ -22,20 +14,11 @@ pub enum Code {
     SetAddress,
     ReadAddress,
     ReadFWVersion,
+    /// Sent with `PROBE_ID_COMMAND` in the type byte, answered with the CPU ID
+    ProbeId,
     Unknown(u8, u8),
 }
 
-pub fn expected_response_len(code: Code) -> u32 {
-    match code {
-        Code::SetAddress => 15,
-        Code::ReadInput => 15,
-        Code::SetOutput => 14,
-        Code::ReadFWVersion => 18,
-        Code::ReadAddress => 18,
-        _ => 0,
-    }
-}
-
 impl From<(u8, u8)> for Code {
     fn from(code: (u8, u8)) -> Self {
         let (first, second) = code;
@@ -46,31 +29,154 @@ impl From<(u8, u8)> for Code {
 
 impl From<u16> for Code {
     fn from(num: u16) -> Self {
-        for &(associated_num, code) in &CODE_U16 {
-            if num == associated_num {
-                return code;
-            }
+        // A probe is told by the type byte of the packet, its number is not its own
+        match COMMANDS
+            .iter()
+            .find(|spec| spec.number == num && spec.code != Code::ProbeId)
+        {
+            Some(spec) => spec.code,
+            None => Self::Unknown((num >> 8) as u8, num as u8),
         }
-
-        Self::Unknown((num >> 8) as u8, num as u8)
     }
 }
 
-impl Into<u16> for Code {
-    fn into(self) -> u16 {
-        for &(num, code) in &CODE_U16 {
-            if code == self {
-                return num;
+impl From<Code> for u16 {
+    fn from(code: Code) -> Self {
+        match code {
+            Code::Unknown(first, second) => ((first as u16) << 8) | (second as u16),
+            code => {
+                spec(code)
+                    .expect("every known code is registered in COMMANDS")
+                    .number
             }
         }
+    }
+}
 
-        0xFFFF
+/// A field of the data sent with a command
+#[derive(Copy, Clone, Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub len: usize,
+}
+
+/// Description of a command known to the application
+pub struct CommandSpec {
+    pub code: Code,
+    pub number: u16,
+    /// Name used to look the command up
+    pub name: &'static str,
+    pub request: &'static [Field],
+    /// Bytes of data carried by the response
+    pub response_len: usize,
+    /// Meaning of the response data, `None` if the data does not fit
+    pub decode: fn(&[u8]) -> Option<String>,
+}
+
+impl CommandSpec {
+    pub fn request_len(&self) -> usize {
+        self.request.iter().map(|field| field.len).sum()
     }
+
+    /// Names of the request fields, e.g. "relay, on".
+    pub fn request_fields(&self) -> String {
+        self.request
+            .iter()
+            .map(|field| field.name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The address of the board is repeated in the data of most commands
+const DESTINATION: Field = Field {
+    name: "destination",
+    len: DeviceAddress::LEN,
+};
+
+pub static COMMANDS: [CommandSpec; 6] = [
+    CommandSpec {
+        code: Code::ReadInput,
+        number: 0x0101,
+        name: "read_input",
+        request: &[],
+        response_len: InputState::LEN,
+        decode: |data| {
+            InputState::decode(data)
+                .ok()
+                .map(|inputs| format!("ingressi 0x{:02X}", inputs.0))
+        },
+    },
+    CommandSpec {
+        code: Code::SetOutput,
+        number: 0xFF01,
+        name: "set_output",
+        request: &[
+            Field {
+                name: "relay",
+                len: 1,
+            },
+            Field { name: "on", len: 1 },
+        ],
+        response_len: 0,
+        decode: |data| data.is_empty().then(String::new),
+    },
+    CommandSpec {
+        code: Code::SetAddress,
+        number: 0xFF03,
+        name: "set_address",
+        request: &[DESTINATION],
+        response_len: 1,
+        decode: |data| match data {
+            [result] => Some(format!("esito {}", result)),
+            _ => None,
+        },
+    },
+    CommandSpec {
+        code: Code::ReadAddress,
+        number: 0xFF04,
+        name: "read_address",
+        request: &[DESTINATION],
+        response_len: DeviceAddress::LEN,
+        decode: |data| {
+            DeviceAddress::decode(data)
+                .ok()
+                .map(|address| format!("indirizzo {}", address))
+        },
+    },
+    CommandSpec {
+        code: Code::ReadFWVersion,
+        number: 0x400A,
+        name: "read_fw_version",
+        request: &[DESTINATION],
+        response_len: FwVersion::LEN,
+        decode: |data| {
+            FwVersion::decode(data)
+                .ok()
+                .map(|version| format!("versione {}", version))
+        },
+    },
+    CommandSpec {
+        code: Code::ProbeId,
+        number: 0x0000,
+        name: "probe_id",
+        request: &[],
+        response_len: CpuId::LEN,
+        decode: |data| CpuId::decode(data).ok().map(|id| format!("CPU {}", id)),
+    },
+];
+
+/// Finds a command by name.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+pub fn spec(code: Code) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.code == code)
 }
 
 pub fn crc(data: &[u8]) -> u8 {
-    data.iter()
-        .fold(0 as u8, |sum, item| sum.wrapping_add(*item))
+    data.iter().fold(0u8, |sum, item| sum.wrapping_add(*item))
 }
 
 pub struct Command {
@@ -93,16 +199,21 @@ impl core::fmt::Display for Command {
             write!(f, "0x{:02X} ", b)?;
         }
 
-        write!(f, "\n")
+        writeln!(f)
     }
 }
 
 impl Command {
     pub const MIN_PACKET_LEN: usize = 0x0F;
 
+    pub fn payload(&self) -> &[u8] {
+        &self.data[0..self.data_len as usize]
+    }
+
+    /// Data beyond 255 bytes is dropped, `serialize` rejects what does not fit a packet.
     pub fn new(code: Code, source: [u8; 4], destination: [u8; 4], data: &[u8]) -> Self {
         Command {
-            data_len: data.len() as u8,
+            data_len: data.len().min(u8::MAX as usize) as u8,
             destination,
             source,
             code,
@@ -110,77 +221,174 @@ impl Command {
         }
     }
 
-    pub fn parse(buffer: &[u8]) -> Option<Command> {
-        if buffer.len() < Self::MIN_PACKET_LEN {
-            return None;
-        }
-
-        if buffer[0] == PREAMBLE {
-            let start = 0;
-            let len = buffer[start + 2];
+    pub fn parse(buffer: &[u8]) -> Result<Command, PacketError> {
+        // Caso speciale del comando scrivi ID CPU: any type byte other than
+        // the probe one is taken as a normal command
+        let packet = frame(buffer, Self::MIN_PACKET_LEN)?;
+        let code = match packet[1] {
+            PROBE_ID_COMMAND => Code::ProbeId,
+            _ => Code::from((packet[12], packet[13])),
+        };
 
-            if (len as usize) < Self::MIN_PACKET_LEN {
-                return None;
-            }
+        Ok(Command::new(
+            code,
+            array_init::array_init(|x| packet[8 + x]),
+            array_init::array_init(|x| packet[4 + x]),
+            &packet[14..packet.len() - 1],
+        ))
+    }
 
-            if buffer.len() >= len as usize {
-                let crc = crc(&buffer[0..len as usize - 1]);
-                if buffer[len as usize - 1] == crc {
-                    // Caso speciale del comando scrivi ID CPU
-                    let start: usize = 12;
-                    return Some(Command {
-                        data_len: len - Self::MIN_PACKET_LEN as u8,
-                        destination: array_init::array_init(|x| buffer[4 + x]),
-                        source: array_init::array_init(|x| buffer[8 + x]),
-                        code: Code::from((buffer[start], buffer[start + 1])),
-                        data: array_init::array_init(|x| {
-                            let index = start + 2 + x;
-                            if index < buffer.len() - 1 {
-                                buffer[index]
-                            } else {
-                                0
-                            }
-                        }),
-                    });
-                }
-            }
+    pub fn serialize(self, buffer: &mut [u8]) -> Result<usize, PacketError> {
+        let dlen = self.data_len as usize;
+        let len = dlen + HEADER_LENGTH;
+        if len > u8::MAX as usize {
+            return Err(PacketError::TooLong(dlen));
         }
-        return None;
-    }
+        let available = buffer.len();
+        let buffer = buffer.get_mut(0..len).ok_or(PacketError::Short {
+            needed: len,
+            available,
+        })?;
 
-    pub fn serialize(self, buffer: &mut [u8]) -> usize {
         buffer[0] = PREAMBLE;
-        buffer[1] = 1;
+        buffer[1] = match self.code {
+            Code::ProbeId => PROBE_ID_COMMAND,
+            _ => NORMAL_COMMAND,
+        };
+        buffer[2] = len as u8;
         buffer[3] = 0;
         buffer[4..8].clone_from_slice(&self.destination[0..4]);
         buffer[8..12].clone_from_slice(&self.source[0..4]);
 
-        let dlen = self.data_len as usize;
-        buffer[2] = HEADER_LENGTH as u8 + self.data_len;
-
         let cmdcode: u16 = self.code.into();
-        buffer[12] = ((cmdcode >> 8) & 0xFF) as u8;
-        buffer[13] = (cmdcode & 0xFF) as u8;
+        buffer[12..14].clone_from_slice(&cmdcode.to_be_bytes());
         buffer[14..14 + dlen].clone_from_slice(&self.data[0..dlen]);
-        let c = crc(&buffer[0..14 + dlen]);
-        buffer[14 + dlen] = c;
-        dlen + HEADER_LENGTH
+        buffer[len - 1] = crc(&buffer[0..len - 1]);
+        Ok(len)
     }
 }
 
-#[derive(Copy, Clone)]
+/// Why a packet could not be parsed or serialized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    /// The buffer holds fewer bytes than the packet needs
+    Short {
+        needed: usize,
+        available: usize,
+    },
+    NoPreamble(u8),
+    /// The length byte is smaller than the packet header, or not the one
+    /// required by the type of packet
+    BadLength(u8),
+    BadCrc {
+        expected: u8,
+        found: u8,
+    },
+    /// The type byte at offset 1 is not the expected one
+    BadType(u8),
+    /// The data does not fit the length byte
+    TooLong(usize),
+}
+
+impl core::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PacketError::Short { needed, available } => {
+                write!(f, "servono {} byte, disponibili {}", needed, available)
+            }
+            PacketError::NoPreamble(byte) => write!(f, "preambolo mancante (0x{:02X})", byte),
+            PacketError::BadLength(len) => write!(f, "lunghezza non valida ({})", len),
+            PacketError::BadCrc { expected, found } => write!(
+                f,
+                "CRC errato (0x{:02X} invece di 0x{:02X})",
+                found, expected
+            ),
+            PacketError::BadType(byte) => write!(f, "tipo di pacchetto 0x{:02X}", byte),
+            PacketError::TooLong(len) => write!(f, "{} byte di dati sono troppi", len),
+        }
+    }
+}
+
+/// Checks preamble, length and CRC of the packet at the start of `buffer`
+/// and returns exactly its bytes.
+fn frame(buffer: &[u8], min_len: usize) -> Result<&[u8], PacketError> {
+    if buffer.len() < min_len {
+        return Err(PacketError::Short {
+            needed: min_len,
+            available: buffer.len(),
+        });
+    }
+    if buffer[0] != PREAMBLE {
+        return Err(PacketError::NoPreamble(buffer[0]));
+    }
+
+    let len = buffer[2] as usize;
+    if len < min_len {
+        return Err(PacketError::BadLength(buffer[2]));
+    }
+    let packet = buffer.get(0..len).ok_or(PacketError::Short {
+        needed: len,
+        available: buffer.len(),
+    })?;
+
+    let expected = crc(&packet[0..len - 1]);
+    let found = packet[len - 1];
+    if found != expected {
+        return Err(PacketError::BadCrc { expected, found });
+    }
+
+    Ok(packet)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResponseType {
     Usual,
+    /// Answer to `PROBE_ID_COMMAND`, the CPU ID is both the source and the data
     CpuID,
     LegacyCipher,
 }
 
+/// Length of the answer to `PROBE_ID_COMMAND`
+pub const CPU_ID_PACKET_LEN: usize = 18;
+
+/// Fixed content of a legacy cipher response from the status byte on
+const LEGACY_CIPHER: [u8; 9] = [0, 33, 1, 5, 5, 4, 2, 255, 37];
+const LEGACY_CIPHER_PACKET_LEN: usize = 22;
+
+/// Status byte at offset 12 of a usual response
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResponseStatus {
+    Ok,
+    Error,
+    Unknown(u8),
+}
+
+impl From<u8> for ResponseStatus {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => ResponseStatus::Ok,
+            1 => ResponseStatus::Error,
+            other => ResponseStatus::Unknown(other),
+        }
+    }
+}
+
+impl From<ResponseStatus> for u8 {
+    fn from(status: ResponseStatus) -> Self {
+        match status {
+            ResponseStatus::Ok => 0,
+            ResponseStatus::Error => 1,
+            ResponseStatus::Unknown(byte) => byte,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Response {
     pub data_len: u8,
     pub destination: [u8; 4],
     pub source: [u8; 4],
-    pub error: bool,
+    pub status: ResponseStatus,
     pub response_type: ResponseType,
     pub data: [u8; 256],
 }
@@ -188,110 +396,117 @@ pub struct Response {
 impl Response {
     pub const MIN_PACKET_LEN: usize = 0x0E;
 
+    pub fn is_ok(&self) -> bool {
+        self.status == ResponseStatus::Ok
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[0..self.data_len as usize]
+    }
+
+    /// Data beyond 255 bytes is dropped, `serialize` rejects what does not fit a packet.
     pub fn ok(destination: [u8; 4], source: [u8; 4], data: &[u8]) -> Self {
-        assert!(data.len() < 256);
         Response {
-            data_len: data.len() as u8,
+            data_len: data.len().min(u8::MAX as usize) as u8,
             destination,
             source,
             response_type: ResponseType::Usual,
-            error: false,
+            status: ResponseStatus::Ok,
             data: array_init::array_init(|i| if i < data.len() { data[i] } else { 0 }),
         }
     }
 
+    /// Answer of the board `cpu_id` to `PROBE_ID_COMMAND`.
+    pub fn cpu_id(destination: [u8; 4], cpu_id: [u8; 4]) -> Self {
+        Response {
+            response_type: ResponseType::CpuID,
+            ..Response::ok(destination, cpu_id, &cpu_id)
+        }
+    }
+
     pub fn err(destination: [u8; 4], source: [u8; 4], data: &[u8]) -> Self {
-        assert!(data.len() < 256);
         Response {
-            data_len: data.len() as u8,
+            data_len: data.len().min(u8::MAX as usize) as u8,
             destination,
             source,
-            error: true,
+            status: ResponseStatus::Error,
             response_type: ResponseType::Usual,
             data: array_init::array_init(|i| if i < data.len() { data[i] } else { 0 }),
         }
     }
 
-    pub fn serialize(self, buffer: &mut [u8]) -> usize {
+    pub fn serialize(self, buffer: &mut [u8]) -> Result<usize, PacketError> {
+        let len = match self.response_type {
+            ResponseType::Usual | ResponseType::CpuID => {
+                self.data_len as usize + RESPONSE_HEADER_LENGTH
+            }
+            ResponseType::LegacyCipher => LEGACY_CIPHER_PACKET_LEN,
+        };
+        if len > u8::MAX as usize {
+            return Err(PacketError::TooLong(self.data_len as usize));
+        }
+        if self.response_type == ResponseType::CpuID && len != CPU_ID_PACKET_LEN {
+            return Err(PacketError::BadLength(len as u8));
+        }
+        let available = buffer.len();
+        let buffer = buffer.get_mut(0..len).ok_or(PacketError::Short {
+            needed: len,
+            available,
+        })?;
+
         buffer[0] = PREAMBLE;
         buffer[3] = 0;
         buffer[4..8].clone_from_slice(&self.destination[0..4]);
         buffer[8..12].clone_from_slice(&self.source[0..4]);
 
         match self.response_type {
-            ResponseType::Usual => {
-                buffer[1] = 1;
+            ResponseType::Usual | ResponseType::CpuID => {
+                buffer[1] = match self.response_type {
+                    ResponseType::CpuID => PROBE_ID_COMMAND,
+                    _ => NORMAL_COMMAND,
+                };
                 let dlen = self.data_len as usize;
-                buffer[2] = 14 + self.data_len;
-                buffer[12] = if self.error { 1 } else { 0 };
+                buffer[2] = len as u8;
+                buffer[12] = self.status.into();
                 buffer[13..13 + dlen].clone_from_slice(&self.data[0..dlen]);
-                let c = crc(&buffer[0..13 + dlen]);
-                buffer[13 + dlen] = c;
-                dlen + RESPONSE_HEADER_LENGTH
-            }
-
-            ResponseType::CpuID => {
-                buffer[1] = 0;
-                buffer[2] = 18;
-                buffer[12] = 0;
-                buffer[13..17].clone_from_slice(&self.source[0..4]);
-                buffer[17] = crc(&buffer[0..17]);
-                18
+                buffer[13 + dlen] = crc(&buffer[0..13 + dlen]);
             }
 
             ResponseType::LegacyCipher => {
-                let zeros: [u8; 9] = [0, 33, 1, 5, 5, 4, 2, 255, 37];
                 //0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0x21, 1, 5, 5, 4, 2, 0xff, 0x25, 0x70, ];
-                buffer[1] = 1;
-                buffer[2] = 22;
-                buffer[12..21].clone_from_slice(&zeros);
-                buffer[21] = crc(&buffer[0..22]);
-                22
+                buffer[1] = NORMAL_COMMAND;
+                buffer[2] = LEGACY_CIPHER_PACKET_LEN as u8;
+                buffer[12..21].clone_from_slice(&LEGACY_CIPHER);
+                // The checksum excludes its own byte, as for every other packet
+                buffer[21] = crc(&buffer[0..21]);
             }
         }
-    }
 
-    pub fn parse(buffer: &[u8]) -> Option<Response> {
-        if buffer.len() < Self::MIN_PACKET_LEN {
-            return None;
-        }
-
-        if buffer[0] == PREAMBLE && buffer[1] == 1 {
-            let start = 0;
-            let len = buffer[start + 2];
+        Ok(len)
+    }
 
-            if (len as usize) < Self::MIN_PACKET_LEN {
-                return None;
+    pub fn parse(buffer: &[u8]) -> Result<Response, PacketError> {
+        let packet = frame(buffer, Self::MIN_PACKET_LEN)?;
+        let response_type = match packet[1] {
+            NORMAL_COMMAND
+                if packet.len() == LEGACY_CIPHER_PACKET_LEN && packet[12..21] == LEGACY_CIPHER =>
+            {
+                ResponseType::LegacyCipher
             }
+            NORMAL_COMMAND => ResponseType::Usual,
+            PROBE_ID_COMMAND if packet.len() == CPU_ID_PACKET_LEN => ResponseType::CpuID,
+            PROBE_ID_COMMAND => return Err(PacketError::BadLength(packet[2])),
+            other => return Err(PacketError::BadType(other)),
+        };
 
-            if buffer.len() >= len as usize {
-                let data_len = len - Self::MIN_PACKET_LEN as u8;
-                let crc = crc(&buffer[0..len as usize - 1]);
-
-                if buffer[len as usize - 1] == crc {
-                    return Some(Response {
-                        error: false, //buffer[12] != 1,
-                        response_type: ResponseType::Usual,
-                        data_len,
-                        destination: array_init::array_init(|x| buffer[4 + x]),
-                        source: array_init::array_init(|x| buffer[8 + x]),
-                        data: array_init::array_init(|x| {
-                            if x < data_len as usize {
-                                let index = 13 + x;
-                                buffer[index]
-                            } else {
-                                0
-                            }
-                        }),
-                    });
-                } else {
-                    log::warn!("Invalid CRC ({} - {})!", buffer[len as usize - 1], crc);
-                }
-            } else {
-                log::warn!("Invalid len ({} - {})!", buffer.len(), len);
-            }
-        }
-        return None;
+        let mut response = Response::ok(
+            array_init::array_init(|x| packet[4 + x]),
+            array_init::array_init(|x| packet[8 + x]),
+            &packet[13..packet.len() - 1],
+        );
+        response.status = ResponseStatus::from(packet[12]);
+        response.response_type = response_type;
+        Ok(response)
     }
 }
 
@@ -299,14 +514,621 @@ impl core::fmt::Display for Response {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "Response from {:?} to {:?}, (error: {}) {} bytes of data:\n\t",
-            self.source, self.destination, self.error, self.data_len
+            "Response from {:?} to {:?}, (status: {:?}) {} bytes of data:\n\t",
+            self.source, self.destination, self.status, self.data_len
         )?;
 
         for b in &self.data[0..self.data_len as usize] {
             write!(f, "0x{:02X} ", b)?;
         }
 
-        write!(f, "\n")
+        writeln!(f)
+    }
+}
+
+/// Data of a command or response whose size does not match its payload type
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LengthError {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl core::fmt::Display for LengthError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "attesi {} byte, ricevuti {}", self.expected, self.actual)
+    }
+}
+
+/// Typed content of the data carried by a command or a response.
+pub trait Payload: Sized {
+    const LEN: usize;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(data: &[u8]) -> Result<Self, LengthError>;
+}
+
+/// Takes exactly `N` bytes out of `data`.
+fn fixed<const N: usize>(data: &[u8]) -> Result<[u8; N], LengthError> {
+    data.try_into().map_err(|_| LengthError {
+        expected: N,
+        actual: data.len(),
+    })
+}
+
+/// Response to `ReadFWVersion`, the last byte is reserved
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FwVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Payload for FwVersion {
+    const LEN: usize = 4;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.major, self.minor, self.patch, 0]
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, LengthError> {
+        let [major, minor, patch, _] = fixed(data)?;
+        Ok(FwVersion {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl core::fmt::Display for FwVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl core::str::FromStr for FwVersion {
+    type Err = String;
+
+    /// Parses a version written as "major.minor.patch".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u8> = text
+            .split('.')
+            .map(|p| p.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid version {}", text))?;
+
+        match parts[..] {
+            [major, minor, patch] => Ok(FwVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(format!("invalid version {}", text)),
+        }
+    }
+}
+
+/// Address of a board, sent with most commands and returned by `ReadAddress`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceAddress(pub u32);
+
+impl Payload for DeviceAddress {
+    const LEN: usize = 4;
+
+    fn encode(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, LengthError> {
+        Ok(DeviceAddress(u32::from_be_bytes(fixed(data)?)))
+    }
+}
+
+impl core::fmt::Display for DeviceAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:08X}", self.0)
+    }
+}
+
+/// Unique identifier of the microcontroller, returned by `ProbeId`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CpuId(pub u32);
+
+impl Payload for CpuId {
+    const LEN: usize = 4;
+
+    fn encode(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, LengthError> {
+        Ok(CpuId(u32::from_be_bytes(fixed(data)?)))
+    }
+}
+
+impl core::fmt::Display for CpuId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:08X}", self.0)
+    }
+}
+
+/// Response to `ReadInput`, one bit for each input
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InputState(pub u8);
+
+impl InputState {
+    pub fn is_on(&self, input: u8) -> bool {
+        input < 8 && self.0 & (1 << input) != 0
+    }
+}
+
+impl Payload for InputState {
+    const LEN: usize = 1;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.0]
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, LengthError> {
+        let [inputs] = fixed(data)?;
+        Ok(InputState(inputs))
+    }
+}
+
+/// Data of `SetOutput`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutputCommand {
+    pub relay: u8,
+    pub on: bool,
+}
+
+impl Payload for OutputCommand {
+    const LEN: usize = 2;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.relay, self.on as u8]
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, LengthError> {
+        let [relay, on] = fixed(data)?;
+        Ok(OutputCommand { relay, on: on != 0 })
+    }
+}
+
+/// Reason why the decoder discarded received bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The packet as framed by its length byte, with the wrong checksum
+    BadCrc(Vec<u8>),
+    /// The length byte is shorter than any valid packet
+    BadLength(usize),
+    /// The packet is framed correctly but is not a valid command or response
+    Malformed(Vec<u8>),
+}
+
+/// Incremental packet decoder.
+///
+/// Bytes coming from the line can be pushed in chunks of any size; the decoder
+/// skips anything that does not start with `PREAMBLE`, frames packets using the
+/// length byte at offset 2 and drops those with an invalid CRC.
+#[derive(Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    last_error: Option<FrameError>,
+}
+
+impl Decoder {
+    /// Upper bound for the bytes kept while waiting for the rest of a packet.
+    const MAX_BUFFER_LEN: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+
+        if self.buffer.len() > Self::MAX_BUFFER_LEN {
+            let excess = self.buffer.len() - Self::MAX_BUFFER_LEN;
+            self.buffer.drain(0..excess);
+        }
+    }
+
+    /// Returns why bytes were last discarded, if they were.
+    pub fn take_error(&mut self) -> Option<FrameError> {
+        self.last_error.take()
+    }
+
+    /// Bytes received but not yet consumed by a complete packet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Extracts the next complete packet with a valid CRC, if any.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        while let Some(result) = self.try_next_frame() {
+            match result {
+                Ok(frame) => return Some(frame),
+                Err(e) => self.last_error = Some(e),
+            }
+        }
+        None
+    }
+
+    /// Extracts the next complete packet, or the reason why the bytes at the
+    /// head of the buffer were discarded.
+    pub fn try_next_frame(&mut self) -> Option<Result<Vec<u8>, FrameError>> {
+        match self.buffer.iter().position(|&b| b == PREAMBLE) {
+            Some(start) => {
+                if start > 0 {
+                    log::warn!("Discarding {} bytes before preamble", start);
+                    self.buffer.drain(0..start);
+                }
+            }
+            None => {
+                self.buffer.clear();
+                return None;
+            }
+        }
+
+        if self.buffer.len() < 3 {
+            return None;
+        }
+
+        let len = self.buffer[2] as usize;
+        if len < Response::MIN_PACKET_LEN {
+            // Not a real preamble, look for the next one
+            self.buffer.remove(0);
+            return Some(Err(FrameError::BadLength(len)));
+        }
+
+        if self.buffer.len() < len {
+            return None;
+        }
+
+        let crc = crc(&self.buffer[0..len - 1]);
+        if self.buffer[len - 1] != crc {
+            log::warn!("Invalid CRC ({} - {})!", self.buffer[len - 1], crc);
+            let frame = self.buffer[0..len].to_vec();
+            self.buffer.remove(0);
+            return Some(Err(FrameError::BadCrc(frame)));
+        }
+
+        Some(Ok(self.buffer.drain(0..len).collect()))
+    }
+
+    pub fn next_response(&mut self) -> Option<Response> {
+        while let Some(frame) = self.next_frame() {
+            if let Ok(response) = Response::parse(&frame) {
+                return Some(response);
+            }
+            self.last_error = Some(FrameError::Malformed(frame));
+        }
+        None
+    }
+
+    pub fn next_command(&mut self) -> Option<Command> {
+        while let Some(frame) = self.next_frame() {
+            if let Ok(command) = Command::parse(&frame) {
+                return Some(command);
+            }
+            self.last_error = Some(FrameError::Malformed(frame));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MAX_COMMAND_DATA: usize = u8::MAX as usize - HEADER_LENGTH;
+    const MAX_RESPONSE_DATA: usize = u8::MAX as usize - RESPONSE_HEADER_LENGTH;
+
+    proptest! {
+        #[test]
+        fn command_round_trip(
+            code: u16,
+            source: [u8; 4],
+            destination: [u8; 4],
+            data in prop::collection::vec(any::<u8>(), 0..=MAX_COMMAND_DATA),
+        ) {
+            let mut buffer = [0; 256];
+            let len = Command::new(Code::from(code), source, destination, &data)
+                .serialize(&mut buffer)
+                .unwrap();
+            prop_assert_eq!(len, data.len() + HEADER_LENGTH);
+
+            let parsed = Command::parse(&buffer[0..len]).unwrap();
+            prop_assert_eq!(u16::from(parsed.code), code);
+            prop_assert_eq!(parsed.source, source);
+            prop_assert_eq!(parsed.destination, destination);
+            prop_assert_eq!(parsed.payload(), &data[..]);
+        }
+
+        #[test]
+        fn response_round_trip(
+            status: u8,
+            source: [u8; 4],
+            destination: [u8; 4],
+            data in prop::collection::vec(any::<u8>(), 0..=MAX_RESPONSE_DATA),
+        ) {
+            let mut response = Response::ok(destination, source, &data);
+            response.status = ResponseStatus::from(status);
+
+            let mut buffer = [0; 256];
+            let len = response.serialize(&mut buffer).unwrap();
+            prop_assert_eq!(len, data.len() + RESPONSE_HEADER_LENGTH);
+
+            let parsed = Response::parse(&buffer[0..len]).unwrap();
+            prop_assert_eq!(u8::from(parsed.status), status);
+            prop_assert_eq!(parsed.source, source);
+            prop_assert_eq!(parsed.destination, destination);
+            prop_assert_eq!(parsed.payload(), &data[..]);
+        }
+
+        #[test]
+        fn cpu_id_round_trip(destination: [u8; 4], cpu_id: [u8; 4]) {
+            let mut buffer = [0; 256];
+            let len = Response::cpu_id(destination, cpu_id)
+                .serialize(&mut buffer)
+                .unwrap();
+            prop_assert_eq!(len, CPU_ID_PACKET_LEN);
+
+            let parsed = Response::parse(&buffer[0..len]).unwrap();
+            prop_assert_eq!(parsed.response_type, ResponseType::CpuID);
+            prop_assert_eq!(parsed.destination, destination);
+            prop_assert_eq!(parsed.payload(), &cpu_id[..]);
+        }
+
+        #[test]
+        fn response_from_bytes_round_trip(
+            (kind, data) in prop_oneof![
+                any::<[u8; 4]>().prop_map(|data| (PROBE_ID_COMMAND, data.to_vec())),
+                prop::collection::vec(any::<u8>(), 0..=MAX_RESPONSE_DATA)
+                    .prop_map(|data| (NORMAL_COMMAND, data)),
+            ],
+            destination: [u8; 4],
+            source: [u8; 4],
+            status: u8,
+        ) {
+            let mut packet = vec![PREAMBLE, kind, (data.len() + RESPONSE_HEADER_LENGTH) as u8, 0];
+            packet.extend_from_slice(&destination);
+            packet.extend_from_slice(&source);
+            packet.push(status);
+            packet.extend_from_slice(&data);
+            packet.push(crc(&packet));
+
+            let parsed = Response::parse(&packet).unwrap();
+            prop_assert_eq!(u8::from(parsed.status), status);
+            prop_assert_eq!(parsed.payload(), &data[..]);
+
+            let mut buffer = [0; 256];
+            let len = parsed.serialize(&mut buffer).unwrap();
+            prop_assert_eq!(&buffer[0..len], &packet[..]);
+        }
+
+        #[test]
+        fn serialize_into_short_buffer(
+            data in prop::collection::vec(any::<u8>(), 0..300),
+            available in 0usize..300,
+        ) {
+            let mut buffer = vec![0; available];
+
+            let command = Command::new(Code::ReadInput, [0; 4], [0; 4], &data);
+            match command.serialize(&mut buffer) {
+                Ok(len) => prop_assert!(len <= available),
+                Err(PacketError::Short { needed, .. }) => prop_assert!(needed > available),
+                Err(PacketError::TooLong(len)) => prop_assert!(len > MAX_COMMAND_DATA),
+                Err(e) => prop_assert!(false, "unexpected {:?}", e),
+            }
+
+            let response = Response::ok([0; 4], [0; 4], &data);
+            match response.serialize(&mut buffer) {
+                Ok(len) => prop_assert!(len <= available),
+                Err(PacketError::Short { needed, .. }) => prop_assert!(needed > available),
+                Err(PacketError::TooLong(len)) => prop_assert!(len > MAX_RESPONSE_DATA),
+                Err(e) => prop_assert!(false, "unexpected {:?}", e),
+            }
+        }
+
+        #[test]
+        fn parse_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..300)) {
+            let _ = Command::parse(&bytes);
+            let _ = Response::parse(&bytes);
+
+            let mut decoder = Decoder::new();
+            decoder.push(&bytes);
+            while decoder.try_next_frame().is_some() {}
+        }
+
+        #[test]
+        fn parse_corrupted_packet(
+            data in prop::collection::vec(any::<u8>(), 0..32),
+            index: usize,
+            value: u8,
+        ) {
+            let mut buffer = [0; 256];
+            let len = Command::new(Code::SetOutput, [1; 4], [2; 4], &data)
+                .serialize(&mut buffer)
+                .unwrap();
+            buffer[index % len] = value;
+
+            // A single changed byte is always caught by the CRC
+            if let Ok(command) = Command::parse(&buffer[0..len]) {
+                let mut again = [0; 256];
+                let again_len = command.serialize(&mut again).unwrap();
+                prop_assert_eq!(&again[0..again_len], &buffer[0..len]);
+            }
+        }
+    }
+
+    fn response_packet(data: &[u8]) -> Vec<u8> {
+        let mut buffer = [0; 256];
+        let len = Response::ok([1; 4], [2; 4], data)
+            .serialize(&mut buffer)
+            .unwrap();
+        buffer[0..len].to_vec()
+    }
+
+    #[test]
+    fn every_code_registered_once() {
+        let codes = [
+            Code::ReadInput,
+            Code::SetOutput,
+            Code::SetAddress,
+            Code::ReadAddress,
+            Code::ReadFWVersion,
+            Code::ProbeId,
+        ];
+        for code in codes {
+            // Fails to build when a variant is added, add it to `codes` too
+            match code {
+                Code::ReadInput
+                | Code::SetOutput
+                | Code::SetAddress
+                | Code::ReadAddress
+                | Code::ReadFWVersion
+                | Code::ProbeId => (),
+                Code::Unknown(..) => unreachable!(),
+            }
+            let specs = COMMANDS.iter().filter(|spec| spec.code == code).count();
+            assert_eq!(specs, 1, "{:?}", code);
+        }
+        assert_eq!(COMMANDS.len(), codes.len());
+    }
+
+    #[test]
+    fn unknown_codes_round_trip() {
+        for number in [0x0000, 0x1234, 0xFFFF] {
+            assert_eq!(u16::from(Code::from(number)), number);
+        }
+        assert_eq!(Code::from(0xFF04), Code::ReadAddress);
+    }
+
+    #[test]
+    fn decoder_joins_split_reads() {
+        let packet = response_packet(&[0x55, 0xAA]);
+        let mut decoder = Decoder::new();
+
+        for byte in &packet[0..packet.len() - 1] {
+            decoder.push(&[*byte]);
+            assert!(decoder.next_frame().is_none());
+        }
+        decoder.push(&packet[packet.len() - 1..]);
+        assert_eq!(decoder.next_frame(), Some(packet));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn decoder_skips_leading_garbage() {
+        let packet = response_packet(&[0x07]);
+        let mut decoder = Decoder::new();
+
+        decoder.push(&[0xFF, 0x00, 0x13]);
+        decoder.push(&packet);
+        assert_eq!(decoder.next_frame(), Some(packet));
+        assert_eq!(decoder.take_error(), None);
+    }
+
+    #[test]
+    fn decoder_resyncs_after_bad_length() {
+        let packet = response_packet(&[0x07]);
+        let mut decoder = Decoder::new();
+
+        // A preamble followed by a length shorter than any packet
+        decoder.push(&[PREAMBLE, 0x01, 0x03]);
+        decoder.push(&packet);
+        assert_eq!(
+            decoder.try_next_frame(),
+            Some(Err(FrameError::BadLength(3)))
+        );
+        assert_eq!(decoder.try_next_frame(), Some(Ok(packet)));
+        assert_eq!(decoder.try_next_frame(), None);
+    }
+
+    #[test]
+    fn decoder_resyncs_after_bad_crc() {
+        let mut corrupted = response_packet(&[0x01, 0x02]);
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        let packet = response_packet(&[0x03]);
+        let mut decoder = Decoder::new();
+
+        decoder.push(&corrupted);
+        decoder.push(&packet);
+        assert_eq!(
+            decoder.try_next_frame(),
+            Some(Err(FrameError::BadCrc(corrupted)))
+        );
+        // Preambles inside the discarded packet are tried before the next one
+        assert_eq!(decoder.next_frame(), Some(packet));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn decoder_splits_packets_in_one_chunk() {
+        let first = response_packet(&[0x01]);
+        let second = response_packet(&[0x02, 0x03, 0x04, 0x05]);
+        let mut decoder = Decoder::new();
+
+        decoder.push(&[first.clone(), second.clone()].concat());
+        assert_eq!(decoder.next_response().unwrap().payload(), &[0x01]);
+        assert_eq!(
+            decoder.next_response().unwrap().payload(),
+            &[0x02, 0x03, 0x04, 0x05]
+        );
+        assert!(decoder.next_response().is_none());
+    }
+
+    #[test]
+    fn probe_id_command() {
+        let mut buffer = [0; 256];
+        let len = Command::new(Code::ProbeId, [0; 4], [1, 2, 3, 4], &[])
+            .serialize(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer[1], PROBE_ID_COMMAND);
+
+        let parsed = Command::parse(&buffer[0..len]).unwrap();
+        assert_eq!(parsed.code, Code::ProbeId);
+        assert_eq!(parsed.destination, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn normal_command_with_code_zero() {
+        let mut buffer = [0; 256];
+        let len = Command::new(Code::from(0), [0; 4], [1, 2, 3, 4], &[])
+            .serialize(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer[1], NORMAL_COMMAND);
+
+        let parsed = Command::parse(&buffer[0..len]).unwrap();
+        assert_eq!(parsed.code, Code::Unknown(0, 0));
+    }
+
+    #[test]
+    fn cpu_id_with_wrong_data() {
+        let mut response = Response::ok([1; 4], [2; 4], &[1, 2, 3]);
+        response.response_type = ResponseType::CpuID;
+
+        let mut buffer = [0; 256];
+        assert_eq!(
+            response.serialize(&mut buffer),
+            Err(PacketError::BadLength(17))
+        );
+    }
+
+    #[test]
+    fn legacy_cipher_round_trip() {
+        let mut response = Response::ok([1; 4], [2; 4], &[]);
+        response.response_type = ResponseType::LegacyCipher;
+
+        let mut buffer = [0; 256];
+        let len = response.serialize(&mut buffer).unwrap();
+        let parsed = Response::parse(&buffer[0..len]).unwrap();
+        assert_eq!(parsed.response_type, ResponseType::LegacyCipher);
+        assert_eq!(parsed.source, [2; 4]);
     }
 }
@@ -4,12 +4,36 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-mod mblp;
-mod serial;
+pub mod allocator;
+pub mod capture;
+pub mod console;
+pub mod device;
+pub mod error;
+pub mod job;
+pub mod json;
+pub mod manual;
+pub mod mblp;
+pub mod plan;
+pub mod report;
+pub mod serial;
+pub mod traffic;
 
-use crate::model::{Connection, Model};
+pub use error::Error;
+
+use crate::model::{Connection, Model, PortSettings, TestRun};
 use crate::view;
-use mblp::Code;
+use allocator::Allocator;
+use job::{Job, Observer};
+
+/// Delay before reopening a lost port after the first failure, doubled at each further one
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(15);
+
+/// A lost port is present again but could not be opened
+struct Reconnect {
+    failures: u32,
+    next: Instant,
+}
 
 pub struct Controller {
     model: Arc<Mutex<Model>>,
@@ -17,7 +41,14 @@ pub struct Controller {
     rx: mpsc::Receiver<view::Message>,
     tx: mpsc::Sender<view::Message>,
 
-    port: RefCell<Option<Box<dyn serialport::SerialPort>>>,
+    port: RefCell<Option<serial::Link>>,
+    /// Holds the port while it runs
+    job: RefCell<Option<Job>>,
+    /// Inputs polled for the manual panel while it is open
+    poll: RefCell<Option<manual::Poll>>,
+    /// Failed attempts to reopen a lost port
+    reconnect: RefCell<Option<Reconnect>>,
+    virtual_ports: Vec<String>,
 }
 
 impl Controller {
@@ -29,18 +60,27 @@ impl Controller {
             rx,
             tx,
             port: RefCell::new(None),
+            job: RefCell::new(None),
+            poll: RefCell::new(None),
+            reconnect: RefCell::new(None),
+            virtual_ports: Vec::new(),
         }
     }
 
-    pub fn start(self: Self) {
+    /// Adds a port that is not enumerated by the system, like the simulator's.
+    pub fn add_virtual_port(&mut self, name: String) {
+        self.virtual_ports.push(name);
+    }
+
+    pub fn start(self) {
         thread::spawn(move || self.task());
     }
 
-    pub fn get_command_channel(self: &Self) -> mpsc::Sender<view::Message> {
+    pub fn get_command_channel(&self) -> mpsc::Sender<view::Message> {
         self.tx.clone()
     }
 
-    fn modify_model<F>(self: &Self, mut op: F)
+    fn modify_model<F>(&self, mut op: F)
     where
         F: FnMut(&mut Model),
     {
@@ -49,69 +89,168 @@ impl Controller {
         self.ctx.request_repaint();
     }
 
-    fn task(self: Self) {
+    fn traffic(&self) -> Arc<Mutex<traffic::Traffic>> {
+        Arc::clone(&self.model.lock().unwrap().traffic)
+    }
+
+    fn modify_traffic<F>(&self, op: F)
+    where
+        F: FnOnce(&mut traffic::Traffic),
+    {
+        op(&mut self.traffic().lock().unwrap());
+        self.ctx.request_repaint();
+    }
+
+    fn task(self) {
         let mut portts: Instant = Instant::now();
 
+        let port_settings = serial::load_port_settings();
+        let favorites = console::load_favorites();
+        self.modify_model(|m| {
+            m.port_settings = port_settings.clone();
+            m.console.favorites = favorites.clone();
+        });
+
         loop {
-            if let Some(msg) = self.rx.recv_timeout(Duration::from_millis(100)).ok() {
+            if let Ok(msg) = self.rx.recv_timeout(self.next_wait()) {
                 use view::Message::*;
                 match msg {
-                    ConnectToPort(port) => {
-                        let builder = serialport::new(port.clone(), 9600)
-                            .timeout(Duration::from_millis(100))
-                            .stop_bits(serialport::StopBits::One)
-                            .data_bits(serialport::DataBits::Eight);
-                        match builder.open() {
-                            Ok(opened_port) => {
-                                self.port.replace(Some(opened_port));
-                                self.modify_model(|m| {
-                                    m.connection = Connection::Connected(port.clone());
-                                });
-                                self.notify("Connesso!".into());
+                    ConnectToPort(port, port_settings) => {
+                        if self.job.borrow().is_some() {
+                            self.notify(Error::Busy.to_string());
+                        } else {
+                            self.connect(&port, &port_settings);
+                        }
+                    }
 
-                                match self.read_serial_number([0; 4]) {
-                                    Ok(sn) => {
-                                        self.modify_model(|m| {
-                                            m.device_address = format!("{:08X}", sn)
-                                        });
-                                        self.notify(format!("Indirizzo 0x{:08X}", sn));
-                                    }
-                                    Err(e) => {
-                                        self.notify(e);
-                                        self.notify("Indirizzo non recuperata".into());
-                                    }
-                                }
+                    Disconnect => {
+                        // The link is closed when the job hands it back
+                        self.stop_job();
+                        // Relays left on from the manual panel, while the link is still open
+                        self.poll.replace(None);
+                        self.manual_safe_state();
+                        self.port.replace(None);
+                        self.modify_model(|m| m.connection = Connection::Disconnected);
+                        self.notify("Disconnesso".into());
+                    }
+
+                    AutoReconnect(enabled) => {
+                        self.modify_model(|m| m.auto_reconnect = enabled);
+                    }
+
+                    RunAllSteps(enabled) => {
+                        self.modify_model(|m| m.run_all = enabled);
+                    }
+
+                    PauseTraffic(paused) => {
+                        self.modify_traffic(|t| t.paused = paused);
+                    }
+
+                    ClearTraffic => {
+                        self.modify_traffic(|t| t.frames.clear());
+                    }
+
+                    Capture(_) if self.job.borrow().is_some() => {
+                        self.notify(Error::Busy.to_string());
+                    }
+
+                    Capture(path) => {
+                        let started = match (&path, self.port.borrow_mut().as_mut()) {
+                            (Some(path), Some(port)) => port.start_capture(path),
+                            (None, Some(port)) => {
+                                port.stop_capture();
+                                Ok(())
                             }
-                            Err(e) => {
-                                log::warn!("Port connection error: {:?}", e);
-                                self.notify("Errore di connessione!".into());
+                            // Started at the next connection
+                            (_, None) => Ok(()),
+                        };
+                        match started {
+                            Ok(()) => {
+                                self.modify_model(|m| m.capture = path.clone());
+                                self.notify(match &path {
+                                    Some(path) => format!("Cattura su {}", path.display()),
+                                    None => String::from("Cattura fermata"),
+                                });
                             }
+                            Err(e) => self.notify(e),
+                        }
+                    }
+
+                    SendRaw(request) => {
+                        let result = self
+                            .with_port(|p| console::send(p, &request))
+                            .map(|response| console::describe(&request, &response))
+                            .map_err(|e| e.to_string());
+                        self.modify_model(|m| {
+                            m.console.push(console::HistoryEntry {
+                                request: request.clone(),
+                                result: result.clone(),
+                            })
+                        });
+                    }
+
+                    SaveFavorite(favorite) => {
+                        self.modify_model(|m| {
+                            m.console.favorites.retain(|f| f.name != favorite.name);
+                            m.console.favorites.push(favorite.clone());
+                        });
+                        self.save_favorites();
+                    }
+
+                    RemoveFavorite(name) => {
+                        self.modify_model(|m| m.console.favorites.retain(|f| f.name != name));
+                        self.save_favorites();
+                    }
+
+                    ExportTraffic(path, filter) => {
+                        let traffic = self.traffic().lock().unwrap().clone();
+                        match traffic.export(&path, &filter) {
+                            Ok(count) => self.notify(format!(
+                                "{} pacchetti salvati in {}",
+                                count,
+                                path.display()
+                            )),
+                            Err(e) => self.notify(e),
                         }
                     }
 
                     ReadFWVersion(address) => {
                         let destination = u32::to_be_bytes(address);
-                        match self.read_firmware_version(destination) {
-                            Ok(fw @ (fw1, fw2, fw3)) => {
-                                self.modify_model(|m| m.version = Some(fw));
-                                self.notify(format!("Versione firmware {}.{}.{}", fw1, fw2, fw3));
+                        match self.with_port(|p| device::read_firmware_version(p, destination)) {
+                            Ok(version) => {
+                                self.modify_model(|m| m.version = Some(version));
+                                self.notify(format!("Versione firmware {}", version));
                             }
                             Err(e) => {
-                                self.notify(e);
+                                self.notify(e.to_string());
                                 self.notify("Versione firmware non recuperata".into());
                             }
                         }
                     }
 
+                    ReadCpuId(address) => {
+                        let destination = u32::to_be_bytes(address);
+                        match self.with_port(|p| device::read_cpu_id(p, destination)) {
+                            Ok(cpu_id) => {
+                                self.modify_model(|m| m.cpu_id = Some(cpu_id));
+                                self.notify(format!("ID CPU {}", cpu_id));
+                            }
+                            Err(e) => {
+                                self.notify(e.to_string());
+                                self.notify("ID CPU non recuperato".into());
+                            }
+                        }
+                    }
+
                     ReadSerialNumber(address) => {
                         let destination = u32::to_be_bytes(address);
-                        match self.read_serial_number(destination) {
+                        match self.with_port(|p| device::read_serial_number(p, destination)) {
                             Ok(sn) => {
                                 self.modify_model(|m| m.device_address = format!("{:08X}", sn));
                                 self.notify(format!("Indirizzo 0x{:08X}", sn));
                             }
                             Err(e) => {
-                                self.notify(e);
+                                self.notify(e.to_string());
                                 self.notify("Indirizzo non recuperata".into());
                             }
                         }
@@ -119,133 +258,474 @@ impl Controller {
 
                     SetSerialNumber(address) => {
                         let destination = u32::to_be_bytes(address);
-                        match self.set_serial_number(destination) {
-                            Ok(()) => self.notify("Numero di matricola impostato".into()),
+                        match self.with_port(|p| device::set_serial_number(p, destination)) {
+                            Ok(()) => {
+                                self.notify("Numero di matricola impostato".into());
+                                self.record_assignment(address);
+                            }
+                            Err(e @ Error::NotVerified { .. }) => {
+                                self.notify(e.to_string());
+                                self.notify("Impostazione non verificata".into());
+                            }
                             Err(e) => {
-                                self.notify(e);
+                                self.notify(e.to_string());
                                 self.notify("Impostazione fallita".into());
                             }
                         }
                     }
 
+                    AssignSerialNumber => {
+                        let allocator = Allocator::default();
+                        let result = allocator.assign(|serial| {
+                            let destination = u32::to_be_bytes(serial);
+                            self.with_port(|p| device::set_serial_number(p, destination))
+                                .map_err(|e| e.to_string())
+                        });
+
+                        match result {
+                            Ok(serial) => {
+                                self.modify_model(|m| m.device_address = format!("{:08X}", serial));
+                                self.notify(format!("Assegnata la matricola 0x{:08X}", serial));
+                                self.record_assignment(serial);
+                            }
+                            Err(e) => {
+                                self.notify(e);
+                                self.notify("Assegnazione fallita".into());
+                            }
+                        }
+                        self.modify_model(|m| m.next_serial = allocator.peek());
+                    }
+
                     DeviceAddress(address) => {
                         self.modify_model(|m| m.device_address = address.clone());
                     }
 
+                    Scan(addresses) => {
+                        self.notify(format!("Ricerca su {} indirizzi...", addresses.len()));
+                        self.spawn_job(String::from("Ricerca"), move |p, reporter| {
+                            let devices = device::scan(p, &addresses, reporter);
+                            if reporter.is_cancelled() {
+                                reporter.notify("Ricerca interrotta".into());
+                            }
+                            reporter.notify(format!("Trovati {} dispositivi", devices.len()));
+                            reporter.modify_model(|m| m.devices = devices.clone());
+                        });
+                    }
+
                     Test(address) => {
                         let destination = u32::to_be_bytes(address);
-                        match self.test_device(destination) {
-                            Ok(()) => self.notify("Collaudo concluso con successo".into()),
+                        let run_all = self.model.lock().unwrap().run_all;
+                        match plan::TestPlan::load_or_default() {
+                            Ok(mut plan) => {
+                                plan.run_all |= run_all;
+                                self.spawn_job(
+                                    format!("Collaudo {:08X}", address),
+                                    move |p, reporter| {
+                                        let start = Instant::now();
+                                        reporter.modify_model(|m| {
+                                            m.test_run = Some(TestRun::new(address, &plan))
+                                        });
+                                        let (outcome, cpu_id, saved) = report::test_and_record(
+                                            p,
+                                            destination,
+                                            &plan,
+                                            reporter,
+                                        );
+                                        reporter.modify_model(|m| {
+                                            if let Some(run) = m.test_run.as_mut() {
+                                                run.relays = outcome.relays.clone();
+                                                run.faults = outcome.faults.clone();
+                                                run.duration = Some(start.elapsed());
+                                            }
+                                        });
+                                        if let Some(Err(e)) = cpu_id {
+                                            reporter.notify(format!(
+                                                "CPU ID non letto, disattivare read_cpu_id nel piano se la scheda non lo fornisce: {}",
+                                                e
+                                            ));
+                                        }
+                                        if let Err(e) = saved {
+                                            reporter.notify(format!("Report non salvato: {}", e));
+                                        }
+                                        if let Some(Err(e)) = &outcome.safe_state {
+                                            reporter
+                                                .notify(format!("Rele forse ancora accesi: {}", e));
+                                        }
+                                        for failure in &outcome.failures {
+                                            reporter.notify(failure.to_string());
+                                        }
+                                        match outcome.failures.len() {
+                                            0 => reporter
+                                                .notify("Collaudo concluso con successo".into()),
+                                            1 => reporter.notify("Collaudo fallito".into()),
+                                            count => reporter.notify(format!(
+                                                "Collaudo fallito, {} passi non superati",
+                                                count
+                                            )),
+                                        }
+                                    },
+                                )
+                            }
                             Err(e) => {
                                 self.notify(e);
                                 self.notify("Collaudo fallito".into());
                             }
                         }
                     }
+
+                    Stop => {
+                        if self.stop_job() {
+                            self.notify("Arresto in corso...".into());
+                        }
+                    }
+
+                    ManualIo(Some((address, period_ms))) => {
+                        let previous = self
+                            .poll
+                            .replace(Some(manual::Poll::new(address, period_ms)));
+                        if previous.is_none_or(|poll| poll.address != address) {
+                            self.manual_safe_state();
+                            let relays =
+                                plan::TestPlan::load_or_default().unwrap_or_default().relays;
+                            self.modify_model(|m| m.manual = manual::Manual::new(address, relays));
+                        }
+                    }
+
+                    ManualIo(None) => {
+                        self.poll.replace(None);
+                        self.manual_safe_state();
+                    }
+
+                    SetRelay(relay, on) => {
+                        let manual = self.model.lock().unwrap().manual.clone();
+                        // Only the relays of the board shown in the panel
+                        if (relay as usize) < manual.relays.len() {
+                            let result =
+                                self.with_port(|p| manual::set_relay(p, manual.address, relay, on));
+                            self.modify_model(|m| {
+                                let channel = &mut m.manual.relays[relay as usize];
+                                if result.is_ok() {
+                                    channel.on = on;
+                                }
+                                channel.status = result.clone().into();
+                            });
+                        }
+                    }
+
+                    Shutdown(done) => {
+                        // A cancelled test switches its relays off before ending
+                        self.stop_job();
+                        if let Some(job) = self.job.take() {
+                            log::info!("Waiting for the running job before exiting");
+                            if let Some(port) = job.join() {
+                                self.port.borrow_mut().get_or_insert(port);
+                            }
+                        }
+                        self.manual_safe_state();
+                        done.send(()).ok();
+                        return;
+                    }
                 }
             }
 
+            self.poll_job();
+            self.poll_inputs();
+
             if Instant::now().duration_since(portts) > Duration::from_millis(500) {
-                self.modify_model(|m| m.ports = serial::get_serial_ports());
+                let mut ports = serial::get_serial_ports();
+                ports.extend(self.virtual_ports.iter().cloned());
+                let next_serial = Allocator::default().peek();
+                self.modify_model(|m| {
+                    m.ports = ports.clone();
+                    m.next_serial = next_serial.clone();
+                });
+                self.check_port_presence(&ports);
                 portts = Instant::now();
             }
         }
     }
 
-    fn notify(self: &Self, msg: String) {
+    /// Opens the port and reads the address of the connected board.
+    fn connect(&self, port: &str, port_settings: &PortSettings) {
+        match Self::open(port, port_settings) {
+            Ok(opened_port) => self.attach(port, port_settings, opened_port),
+            Err(e) => {
+                log::warn!("Port connection error: {}", e);
+                self.notify("Errore di connessione!".into());
+            }
+        }
+    }
+
+    fn open(port: &str, port_settings: &PortSettings) -> Result<serial::Link, String> {
+        serial::TransportSettings::load_or_default().and_then(|settings| {
+            serial::Link::open(port, port_settings, settings).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Uses `opened_port` as the connected link and reads the address of the board.
+    fn attach(&self, port: &str, port_settings: &PortSettings, mut opened_port: serial::Link) {
+        self.reconnect.replace(None);
+        let traffic = self.traffic();
+        let ctx = self.ctx.clone();
+        opened_port.set_monitor(Box::new(move |frame| {
+            traffic.lock().unwrap().record(frame);
+            ctx.request_repaint();
+        }));
+        let capture = self.model.lock().unwrap().capture.clone();
+        if let Some(path) = capture {
+            if let Err(e) = opened_port.start_capture(&path) {
+                self.modify_model(|m| m.capture = None);
+                self.notify(e);
+            }
+        }
+        self.port.replace(Some(opened_port));
+        self.modify_model(|m| {
+            m.connection = Connection::Connected(port.into());
+            m.port_settings.insert(port.into(), port_settings.clone());
+        });
+        let saved = self.model.lock().unwrap().port_settings.clone();
+        if let Err(e) = serial::save_port_settings(&saved) {
+            log::error!("Cannot save the port settings: {}", e);
+        }
+        self.notify("Connesso!".into());
+
+        match self.with_port(|p| device::read_serial_number(p, [0; 4])) {
+            Ok(sn) => {
+                self.modify_model(|m| m.device_address = format!("{:08X}", sn));
+                self.notify(format!("Indirizzo 0x{:08X}", sn));
+            }
+            Err(e) => {
+                self.notify(e.to_string());
+                self.notify("Indirizzo non recuperata".into());
+            }
+        }
+    }
+
+    /// Notices when the connected adapter is unplugged and, if enabled,
+    /// reconnects once it shows up again.
+    fn check_port_presence(&self, ports: &[String]) {
+        let (connection, auto_reconnect, port_settings) = {
+            let model = self.model.lock().unwrap();
+            (
+                model.connection.clone(),
+                model.auto_reconnect,
+                model.port_settings.clone(),
+            )
+        };
+
+        match connection {
+            Connection::Connected(port) if !ports.contains(&port) => {
+                self.stop_job();
+                self.port.replace(None);
+                self.reconnect.replace(None);
+                self.modify_model(|m| m.connection = Connection::Lost(port.clone()));
+                self.notify(format!("Porta {} scollegata", port));
+            }
+            Connection::Lost(port) if auto_reconnect && ports.contains(&port) => {
+                let due = self
+                    .reconnect
+                    .borrow()
+                    .as_ref()
+                    .is_none_or(|reconnect| Instant::now() >= reconnect.next);
+                if !due {
+                    return;
+                }
+
+                let settings = port_settings.get(&port).cloned().unwrap_or_default();
+                match Self::open(&port, &settings) {
+                    Ok(opened_port) => {
+                        self.notify(format!("Porta {} ricollegata", port));
+                        self.attach(&port, &settings, opened_port);
+                    }
+                    Err(e) => {
+                        let mut reconnect = self.reconnect.borrow_mut();
+                        let reconnect = reconnect.get_or_insert(Reconnect {
+                            failures: 0,
+                            next: Instant::now(),
+                        });
+                        // Reported once, the port may take a while to become usable
+                        if reconnect.failures == 0 {
+                            self.notify(format!("Porta {} presente ma non apribile: {}", port, e));
+                        }
+                        let delay = (RECONNECT_BACKOFF * (1 << reconnect.failures.min(4)))
+                            .min(MAX_RECONNECT_BACKOFF);
+                        reconnect.failures += 1;
+                        reconnect.next = Instant::now() + delay;
+                        log::warn!(
+                            "Cannot reopen {}: {}, retry in {} ms",
+                            port,
+                            e,
+                            delay.as_millis()
+                        );
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn save_favorites(&self) {
+        let favorites = self.model.lock().unwrap().console.favorites.clone();
+        if let Err(e) = console::save_favorites(&favorites) {
+            self.notify(e);
+        }
+    }
+
+    fn notify(&self, msg: String) {
         self.modify_model(|m| m.message(msg.clone()))
     }
 
-    fn read_firmware_version(self: &Self, destination: [u8; 4]) -> Result<(u8, u8, u8), String> {
-        if let Some(ref mut port) = self.port.borrow_mut().as_mut() {
-            let resp = serial::send_command(port, Code::ReadFWVersion, destination, &destination)
-                .map_err(|e| format!("Leggi firmware: {}", e))?;
+    /// Ties the serial just written to the CPU ID of the board in `ASSIGNMENT_FILE`.
+    fn record_assignment(&self, serial: u32) {
+        let (cpu_id, saved) = match self.with_port(|p| Ok(report::record_assignment(p, serial))) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                self.notify(format!("Assegnazione non registrata: {}", e));
+                return;
+            }
+        };
 
-            if resp.data_len > 3 {
-                Ok((resp.data[0], resp.data[1], resp.data[2]))
-            } else {
-                Err(String::from("Risposta non valida"))
+        match cpu_id {
+            Ok(cpu_id) => self.modify_model(|m| m.cpu_id = Some(cpu_id)),
+            Err(e) => {
+                self.modify_model(|m| m.cpu_id = None);
+                self.notify(e.to_string());
+                self.notify("ID CPU non recuperato".into());
             }
-        } else {
-            Err(String::from("Nessuna porta connessa!"))
+        }
+        if let Err(e) = saved {
+            self.notify(e);
         }
     }
 
-    fn read_serial_number(self: &Self, destination: [u8; 4]) -> Result<u32, String> {
-        if let Some(ref mut port) = self.port.borrow_mut().as_mut() {
-            let resp = serial::send_command(port, Code::ReadAddress, destination, &destination)
-                .map_err(|e| format!("Leggi indirizzo: {}", e))?;
-
-            if resp.data_len > 3 {
-                Ok(u32::from_be_bytes([
-                    resp.data[0],
-                    resp.data[1],
-                    resp.data[2],
-                    resp.data[3],
-                ]))
-            } else {
-                Err(String::from("Risposta non valida"))
+    /// Hands the port to a job running `op`, unless another job has it.
+    fn spawn_job<F>(&self, name: String, op: F)
+    where
+        F: FnOnce(&mut device::Port, &mut job::Reporter) + Send + 'static,
+    {
+        if self.job.borrow().is_some() {
+            self.notify(Error::Busy.to_string());
+            return;
+        }
+
+        match self.port.borrow_mut().take() {
+            Some(port) => {
+                let job = Job::spawn(name, port, Arc::clone(&self.model), self.ctx.clone(), op);
+                self.job.replace(Some(job));
             }
-        } else {
-            Err(String::from("Nessuna porta connessa!"))
+            None => self.notify(Error::NotConnected.to_string()),
         }
     }
 
-    fn set_serial_number(self: &Self, destination: [u8; 4]) -> Result<(), String> {
-        if let Some(ref mut port) = self.port.borrow_mut().as_mut() {
-            serial::send_command(port, Code::SetAddress, destination, &destination)
-                .map_err(|e| format!("Imposta codice: {}", e))?;
-            Ok(())
-        } else {
-            Err(String::from("Nessuna porta connessa!"))
+    /// Asks the running job to stop at the next step, returns false if there is none.
+    fn stop_job(&self) -> bool {
+        match self.job.borrow().as_ref() {
+            Some(job) => {
+                job.cancel();
+                self.modify_model(|m| {
+                    if let Some(job) = m.job.as_mut() {
+                        job.stopping = true;
+                    }
+                });
+                true
+            }
+            None => false,
         }
     }
 
-    fn test_device(self: &Self, destination: [u8; 4]) -> Result<(), String> {
-        fn check_input(
-            port: &mut Box<dyn serialport::SerialPort>,
-            destination: [u8; 4],
-            step: u16,
-            expected: u8,
-        ) -> Result<(), String> {
-            let response = serial::send_command(port, Code::ReadInput, destination, &[])
-                .map_err(|e| format!("Leggi ingressi {}: {}", step, e))?;
+    /// Takes the port back from a job that ended, unless the connection was
+    /// closed or replaced in the meantime.
+    fn poll_job(&self) {
+        let finished = self
+            .job
+            .borrow()
+            .as_ref()
+            .is_some_and(|job| job.is_finished());
+        if !finished {
+            return;
+        }
+
+        let port = self.job.take().and_then(|job| job.join());
+        self.modify_model(|m| m.job = None);
 
-            if response.data_len < 1 {
-                return Err(String::from("Ingressi non ottenuti"));
+        let connected = self.model.lock().unwrap().is_connected();
+        match port {
+            Some(port) if connected && self.port.borrow().is_none() => {
+                self.port.replace(Some(port));
             }
+            Some(_) => (),
+            None => {
+                log::error!("Job panicked, the port is closed");
+                self.modify_model(|m| m.connection = Connection::Disconnected);
+                self.notify("Operazione terminata con un errore, porta chiusa".into());
+            }
+        }
+    }
+
+    /// Time until the inputs must be polled again, at most the check period of the messages.
+    fn next_wait(&self) -> Duration {
+        let max = Duration::from_millis(100);
+        match self.poll.borrow().as_ref() {
+            Some(poll) => poll.next.saturating_duration_since(Instant::now()).min(max),
+            None => max,
+        }
+    }
 
-            if response.data[0] != expected {
-                Err(String::from(format!(
-                    "Ingressi non validi: mi aspettavo 0x{:02X}, e' arrivato 0x{:02X}",
-                    expected, response.data[0]
-                )))
-            } else {
-                Ok(())
+    /// Reads the inputs for the manual panel when it is time, unless a job has the port.
+    fn poll_inputs(&self) {
+        let address = match self.poll.borrow_mut().as_mut() {
+            Some(poll) if poll.next <= Instant::now() => {
+                poll.next = Instant::now() + poll.period;
+                poll.address
             }
+            _ => return,
+        };
+        if self.job.borrow().is_some() {
+            return;
         }
 
-        if let Some(ref mut port) = self.port.borrow_mut().as_mut() {
-            for i in 0..4 {
-                serial::send_command(port, Code::SetOutput, destination, &[i, 0])
-                    .map_err(|e| format!("Spegni rele {}: {}", i, e))?;
+        let result = self.with_port(|p| manual::read_inputs(p, address));
+        self.modify_model(|m| match &result {
+            Ok((inputs, round_trip)) => {
+                m.manual.inputs = Some(*inputs);
+                m.manual.input_status = manual::ChannelStatus::Ok(*round_trip);
             }
+            Err(e) => m.manual.input_status = manual::ChannelStatus::Failed(e.to_string()),
+        });
+    }
 
-            check_input(port, destination, 0, 0x00)?;
+    /// Switches off the relays left on from the manual panel.
+    fn manual_safe_state(&self) {
+        let manual = self.model.lock().unwrap().manual.clone();
+        if !manual.any_on() {
+            return;
+        }
 
-            for i in 0..4 {
-                serial::send_command(port, Code::SetOutput, destination, &[i, 1])
-                    .map_err(|e| format!("Accendi rele {}: {}", i, e))?;
-                thread::sleep(Duration::from_millis(100));
-                check_input(port, destination, 0, 1 << i)?;
-                serial::send_command(port, Code::SetOutput, destination, &[i, 0])
-                    .map_err(|e| format!("Spegni rele {}: {}", i, e))?;
-                thread::sleep(Duration::from_millis(100));
+        let destination = manual.address.to_be_bytes();
+        let relays = manual.relays.len() as u8;
+        match self.with_port(|p| device::safe_state(p, destination, relays)) {
+            Ok(()) => self.modify_model(|m| {
+                for relay in m.manual.relays.iter_mut() {
+                    relay.on = false;
+                }
+            }),
+            Err(e) => {
+                self.notify(e.to_string());
+                self.notify("Rele forse ancora accesi".into());
             }
+        }
+    }
 
-            Ok(())
+    fn with_port<T, F>(&self, op: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut device::Port) -> Result<T, Error>,
+    {
+        if let Some(ref mut port) = self.port.borrow_mut().as_mut() {
+            op(port)
+        } else if self.job.borrow().is_some() {
+            Err(Error::Busy)
         } else {
-            Err(String::from("Nessuna porta connessa!"))
+            Err(Error::NotConnected)
         }
     }
 }
@@ -0,0 +1,239 @@
+// Test sequence description, loaded from a JSON file so that new product
+// variants only need a new plan instead of code changes.
+
+use super::json;
+use super::mblp::FwVersion;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::Path;
+
+/// Plan used by the application when no other is specified
+pub const PLAN_FILE: &str = "collaudo.json";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TestPlan {
+    pub name: String,
+    /// Number of relays on the board, used to switch them all off and for the report
+    pub relays: u8,
+    pub steps: Vec<PlanStep>,
+    /// Go on after a failed step, to find every faulty relay in one run
+    #[serde(default)]
+    pub run_all: bool,
+    /// Read the CPU ID for the report before the steps, off for boards that
+    /// do not answer probes
+    #[serde(default = "enabled")]
+    pub read_cpu_id: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlanStep {
+    /// Description shown to the operator, generated from the action when missing
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    SetOutput {
+        relay: u8,
+        on: bool,
+    },
+    /// Switches off every relay of the board
+    AllOff,
+    Wait {
+        ms: u64,
+    },
+    /// Reads the inputs until the bits selected by `mask` equal `expected`,
+    /// for at most `tolerance_ms`
+    ExpectInputs {
+        expected: u8,
+        #[serde(default = "full_mask")]
+        mask: u8,
+        #[serde(default)]
+        tolerance_ms: u64,
+    },
+    ExpectFirmware {
+        #[serde(deserialize_with = "version")]
+        min: FwVersion,
+        #[serde(deserialize_with = "version")]
+        max: FwVersion,
+    },
+}
+
+fn full_mask() -> u8 {
+    0xFF
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn version<'de, D>(deserializer: D) -> Result<FwVersion, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+impl Default for TestPlan {
+    /// The original Bio minion sequence: 4 relays, each one checked alone.
+    fn default() -> Self {
+        let relays = 4;
+        let step = |action| PlanStep { name: None, action };
+
+        let mut steps = vec![
+            step(Action::AllOff),
+            step(Action::ExpectInputs {
+                expected: 0x00,
+                mask: full_mask(),
+                tolerance_ms: 0,
+            }),
+        ];
+
+        for relay in 0..relays {
+            steps.push(step(Action::SetOutput { relay, on: true }));
+            steps.push(step(Action::Wait { ms: 100 }));
+            steps.push(step(Action::ExpectInputs {
+                expected: 1 << relay,
+                mask: full_mask(),
+                tolerance_ms: 0,
+            }));
+            steps.push(step(Action::SetOutput { relay, on: false }));
+            steps.push(step(Action::Wait { ms: 100 }));
+        }
+
+        TestPlan {
+            name: String::from("Bio minion 4 rele"),
+            relays,
+            steps,
+            run_all: false,
+            read_cpu_id: true,
+        }
+    }
+}
+
+impl TestPlan {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        json::load(path).and_then(|plan: Self| plan.checked(path))
+    }
+
+    /// Loads `PLAN_FILE` if present, the default plan otherwise.
+    pub fn load_or_default() -> Result<Self, String> {
+        let path = Path::new(PLAN_FILE);
+        json::load_or_default(path).and_then(|plan: Self| plan.checked(path))
+    }
+
+    fn checked(self, path: &Path) -> Result<Self, String> {
+        self.validate()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for step in &self.steps {
+            if let Action::SetOutput { relay, .. } = step.action {
+                if relay >= self.relays {
+                    return Err(format!(
+                        "il rele {} non esiste, la scheda ne ha {}",
+                        relay, self.relays
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PlanStep {
+    pub fn description(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.action.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::SetOutput { relay, on: true } => write!(f, "Accendi rele {}", relay),
+            Action::SetOutput { relay, on: false } => write!(f, "Spegni rele {}", relay),
+            Action::AllOff => write!(f, "Spegni tutti i rele"),
+            Action::Wait { ms } => write!(f, "Attendi {} ms", ms),
+            Action::ExpectInputs { expected, mask, .. } if *mask == 0xFF => {
+                write!(f, "Verifica ingressi 0x{:02X}", expected)
+            }
+            Action::ExpectInputs { expected, mask, .. } => write!(
+                f,
+                "Verifica ingressi 0x{:02X} (maschera 0x{:02X})",
+                expected, mask
+            ),
+            Action::ExpectFirmware { min, max } => {
+                write!(f, "Verifica firmware {} - {}", min, max)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_plan() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("plans/bio-minion-4.json");
+        let plan = TestPlan::load(&path).unwrap();
+
+        assert_eq!(plan.relays, 4);
+        assert_eq!(plan.steps.len(), 19);
+        assert!(!plan.run_all);
+        assert!(plan.read_cpu_id);
+        assert!(matches!(
+            plan.steps[0].action,
+            Action::ExpectFirmware { min, max }
+                if min.to_string() == "1.0.0" && max.to_string() == "1.255.255"
+        ));
+        assert_eq!(plan.steps[2].description(), "Verifica ingressi a riposo");
+        assert!(matches!(
+            plan.steps[4].action,
+            Action::ExpectInputs {
+                expected: 1,
+                mask: 0xFF,
+                tolerance_ms: 100
+            }
+        ));
+        assert!(matches!(
+            plan.steps[16].action,
+            Action::ExpectInputs { mask: 0x0F, .. }
+        ));
+    }
+
+    #[test]
+    fn relay_out_of_range() {
+        let plan: TestPlan = serde_json::from_str(
+            r#"{ "name": "", "relays": 2, "steps": [{ "action": "set_output", "relay": 2, "on": true }] }"#,
+        )
+        .unwrap();
+        assert!(plan.validate().is_err());
+    }
+
+    #[test]
+    fn unknown_action() {
+        let plan = serde_json::from_str::<TestPlan>(
+            r#"{ "name": "", "relays": 2, "steps": [{ "action": "blink" }] }"#,
+        );
+        assert!(plan.is_err());
+    }
+
+    #[test]
+    fn default_plan_is_valid() {
+        let plan = TestPlan::default();
+        assert!(plan.validate().is_ok());
+        assert_eq!(plan.steps.len(), 2 + 5 * plan.relays as usize);
+    }
+}
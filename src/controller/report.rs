@@ -0,0 +1,183 @@
+// Durable log of every test attempt, one JSON record per line
+
+use super::device::{self, Port, RelayFault, RelayResult, TestFailure, TestOutcome};
+use super::error::Error;
+use super::job::Observer;
+use super::mblp::{CpuId, FwVersion};
+use super::plan::TestPlan;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::SystemTime;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub const REPORT_FILE: &str = "collaudi.jsonl";
+
+/// Serial numbers written to the boards, with the ID of their microcontroller
+pub const ASSIGNMENT_FILE: &str = "assegnazioni.jsonl";
+
+#[derive(Serialize)]
+pub struct FailureRecord {
+    pub step: String,
+    pub kind: &'static str,
+    pub error: String,
+}
+
+impl FailureRecord {
+    fn new(failure: &TestFailure) -> Self {
+        FailureRecord {
+            step: failure.step.clone(),
+            kind: failure.error.kind(),
+            error: failure.error.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TestRecord {
+    pub timestamp: String,
+    pub port: String,
+    pub plan: String,
+    pub address: String,
+    pub cpu_id: Option<String>,
+    pub firmware: Option<String>,
+    pub passed: bool,
+    pub relays: Vec<RelayResult>,
+    /// The first of `failures`, kept for the existing readers of the log
+    pub failure: Option<FailureRecord>,
+    pub failures: Vec<FailureRecord>,
+    pub faults: Vec<RelayFault>,
+    /// Whether the relays were switched off after a failure
+    pub relays_off: Option<bool>,
+}
+
+impl TestRecord {
+    pub fn new(
+        port: String,
+        plan: &TestPlan,
+        destination: [u8; 4],
+        cpu_id: Option<CpuId>,
+        firmware: Option<FwVersion>,
+        outcome: &TestOutcome,
+    ) -> Self {
+        TestRecord {
+            timestamp: now(),
+            port,
+            plan: plan.name.clone(),
+            address: format!("{:08X}", u32::from_be_bytes(destination)),
+            cpu_id: cpu_id.map(|id| id.to_string()),
+            firmware: firmware.map(|version| version.to_string()),
+            passed: outcome.failures.is_empty(),
+            relays: outcome.relays.clone(),
+            failure: outcome.failures.first().map(FailureRecord::new),
+            failures: outcome.failures.iter().map(FailureRecord::new).collect(),
+            faults: outcome.faults.clone(),
+            relays_off: outcome.safe_state.as_ref().map(Result::is_ok),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AssignmentRecord {
+    pub timestamp: String,
+    pub port: String,
+    pub serial: String,
+    /// Missing if the board did not answer the probe after the assignment
+    pub cpu_id: Option<String>,
+}
+
+impl AssignmentRecord {
+    pub fn new(port: String, serial: u32, cpu_id: Option<CpuId>) -> Self {
+        AssignmentRecord {
+            timestamp: now(),
+            port,
+            serial: format!("{:08X}", serial),
+            cpu_id: cpu_id.map(|id| id.to_string()),
+        }
+    }
+}
+
+fn now() -> String {
+    OffsetDateTime::from(SystemTime::now())
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+pub fn append(record: &TestRecord) -> Result<(), String> {
+    append_line(REPORT_FILE, record)
+}
+
+pub fn append_assignment(record: &AssignmentRecord) -> Result<(), String> {
+    append_line(ASSIGNMENT_FILE, record)
+}
+
+fn append_line(path: &str, record: &impl Serialize) -> Result<(), String> {
+    let line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    writeln!(file, "{}", line)
+        .and_then(|()| file.sync_data())
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Reads the CPU ID of the board that was just given `serial` and ties the two
+/// in the assignment log. The second element tells whether the record could be saved.
+pub fn record_assignment(
+    port: &mut Port,
+    serial: u32,
+) -> (Result<CpuId, Error>, Result<(), String>) {
+    let cpu_id = device::read_cpu_id(port, serial.to_be_bytes());
+
+    let record = AssignmentRecord::new(
+        port.name().unwrap_or_default(),
+        serial,
+        cpu_id.as_ref().ok().copied(),
+    );
+    let saved = append_assignment(&record);
+    if let Err(e) = &saved {
+        log::error!("Cannot write the assignment record: {}", e);
+    }
+
+    (cpu_id, saved)
+}
+
+/// Tests the board and writes the attempt to the report log, whatever its outcome.
+/// The second element is the CPU ID read for the record, when the plan asks for it,
+/// the third tells whether the record could be saved.
+pub fn test_and_record(
+    port: &mut Port,
+    destination: [u8; 4],
+    plan: &TestPlan,
+    observer: &mut dyn Observer,
+) -> (
+    TestOutcome,
+    Option<Result<CpuId, Error>>,
+    Result<(), String>,
+) {
+    let cpu_id = plan
+        .read_cpu_id
+        .then(|| device::read_cpu_id(port, destination));
+    let firmware = device::read_firmware_version(port, destination).ok();
+    let outcome = device::test_device(port, destination, plan, observer);
+
+    let record = TestRecord::new(
+        port.name().unwrap_or_default(),
+        plan,
+        destination,
+        cpu_id.as_ref().and_then(|id| id.as_ref().ok()).copied(),
+        firmware,
+        &outcome,
+    );
+    let saved = append(&record);
+    if let Err(e) = &saved {
+        log::error!("Cannot write the test report: {}", e);
+    }
+
+    (outcome, cpu_id, saved)
+}
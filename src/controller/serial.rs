@@ -1,7 +1,16 @@
-use super::mblp::{expected_response_len, Code, Command, Response};
+use super::capture;
+use super::error::Error;
+use super::json;
+use super::mblp::{self, Code, Command, Decoder, FrameError, Response};
+use super::traffic::{Direction, Frame};
+use crate::model::PortSettings;
+use serde::{Deserialize, Serialize};
 use serialport::{available_ports, SerialPort};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::Vec;
 
 pub fn get_serial_ports() -> Vec<String> {
@@ -17,46 +26,352 @@ pub fn get_serial_ports() -> Vec<String> {
         }
     }
 
-    return result;
+    result
 }
 
+/// Settings loaded from this file when present
+pub const TRANSPORT_FILE: &str = "trasporto.json";
+
+/// How commands are exchanged with the boards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportSettings {
+    /// Time allowed for a response to arrive
+    pub timeout_ms: u64,
+    /// Per-code overrides of `timeout_ms`, keyed by hex code (e.g. "400A")
+    pub code_timeouts_ms: BTreeMap<String, u64>,
+    /// Additional attempts after a timeout or a corrupted response
+    pub retries: u32,
+    /// Delay before the first retry, doubled at each further attempt
+    pub backoff_ms: u64,
+    /// Line silence kept before every command
+    pub inter_frame_gap_ms: u64,
+    /// Discard any pending byte before sending
+    pub flush_before_send: bool,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        TransportSettings {
+            timeout_ms: 200,
+            code_timeouts_ms: BTreeMap::new(),
+            retries: 2,
+            backoff_ms: 50,
+            inter_frame_gap_ms: 20,
+            flush_before_send: true,
+        }
+    }
+}
+
+impl TransportSettings {
+    /// Loads `TRANSPORT_FILE` if present, the default settings otherwise.
+    pub fn load_or_default() -> Result<Self, String> {
+        json::load_or_default(Path::new(TRANSPORT_FILE))
+    }
+
+    pub fn timeout(&self, code: Code) -> Duration {
+        let key = format!("{:04X}", u16::from(code));
+        Duration::from_millis(
+            self.code_timeouts_ms
+                .get(&key)
+                .copied()
+                .unwrap_or(self.timeout_ms),
+        )
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.backoff_ms.saturating_mul(1 << attempt.min(16)))
+    }
+}
+
+/// Last used settings of every port, saved in this file
+pub const PORT_SETTINGS_FILE: &str = "porte.json";
+
+pub fn load_port_settings() -> BTreeMap<String, PortSettings> {
+    json::load_or_default(Path::new(PORT_SETTINGS_FILE)).unwrap_or_else(|e| {
+        log::error!("Cannot load port settings: {}", e);
+        BTreeMap::new()
+    })
+}
+
+pub fn save_port_settings(settings: &BTreeMap<String, PortSettings>) -> Result<(), String> {
+    let text = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    fs::write(PORT_SETTINGS_FILE, text).map_err(|e| format!("{}: {}", PORT_SETTINGS_FILE, e))
+}
+
+/// Receives every frame sent or received on a link
+pub type Monitor = Box<dyn FnMut(Frame) + Send>;
+
+/// An open port together with the transport settings used on it.
+pub struct Link {
+    port: Box<dyn SerialPort>,
+    rs485_rts: bool,
+    pub settings: TransportSettings,
+    monitor: Option<Monitor>,
+    capture: Option<capture::Writer>,
+}
+
+impl Link {
+    pub fn open(
+        name: &str,
+        port_settings: &PortSettings,
+        settings: TransportSettings,
+    ) -> serialport::Result<Self> {
+        let mut port = serialport::new(name, port_settings.baud_rate)
+            .timeout(Duration::from_millis(100))
+            .data_bits(port_settings.data_bits)
+            .parity(port_settings.parity)
+            .stop_bits(port_settings.stop_bits)
+            .flow_control(port_settings.flow_control)
+            .open()?;
+
+        if port_settings.rs485_rts {
+            // Start in reception
+            port.write_request_to_send(false)?;
+        }
+
+        Ok(Link {
+            port,
+            rs485_rts: port_settings.rs485_rts,
+            settings,
+            monitor: None,
+            capture: None,
+        })
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.port.name()
+    }
+
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.monitor = Some(monitor);
+    }
+
+    /// Saves every byte sent and received from now on to `path`.
+    pub fn start_capture(&mut self, path: &Path) -> Result<(), String> {
+        self.capture = Some(capture::Writer::open(path)?);
+        Ok(())
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    fn capture(&mut self, direction: Direction, bytes: &[u8]) {
+        if let Some(writer) = self.capture.as_mut() {
+            if let Err(e) = writer.write(direction, bytes) {
+                log::error!("Capture stopped: {}", e);
+                self.capture = None;
+            }
+        }
+    }
+
+    fn record(&mut self, frame: Frame) {
+        if let Some(monitor) = self.monitor.as_mut() {
+            monitor(frame);
+        }
+    }
+}
+
+fn is_transient(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Timeout { .. }
+            | Error::BadCrc { .. }
+            | Error::BadLength { .. }
+            | Error::UnexpectedPayload { .. }
+    )
+}
+
+/// Sends the command registered as `name`, checking the length of the data
+/// sent and received against the registry.
+pub fn send(
+    link: &mut Link,
+    name: &str,
+    destination: [u8; 4],
+    data: &[u8],
+) -> Result<Response, Error> {
+    let spec = mblp::lookup(name)
+        .ok_or_else(|| Error::InvalidCommand(format!("{} non registrato", name)))?;
+    if data.len() != spec.request_len() {
+        return Err(Error::InvalidCommand(format!(
+            "{} richiede {} byte di dati ({}), non {}",
+            name,
+            spec.request_len(),
+            spec.request_fields(),
+            data.len()
+        )));
+    }
+
+    let response = send_command(link, spec.code, destination, data)?;
+
+    if response.data_len as usize != spec.response_len {
+        return Err(Error::UnexpectedPayload {
+            code: spec.code,
+            destination,
+            data: response.payload().to_vec(),
+        });
+    }
+    Ok(response)
+}
+
+/// Sends a command and waits for its response, retrying as configured.
 pub fn send_command(
-    port: &mut Box<dyn SerialPort>,
+    link: &mut Link,
     code: Code,
     destination: [u8; 4],
     data: &[u8],
-) -> Result<Response, String> {
-    port.clear(serialport::ClearBuffer::All).ok();
-    thread::sleep(Duration::from_millis(20));
+) -> Result<Response, Error> {
+    let mut attempt = 0;
+
+    loop {
+        match exchange(link, code, destination, data) {
+            Err(e) if is_transient(&e) && attempt < link.settings.retries => {
+                let delay = link.settings.backoff(attempt);
+                attempt += 1;
+                log::warn!(
+                    "{}; retry {}/{} in {} ms",
+                    e,
+                    attempt,
+                    link.settings.retries,
+                    delay.as_millis()
+                );
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn exchange(
+    link: &mut Link,
+    code: Code,
+    destination: [u8; 4],
+    data: &[u8],
+) -> Result<Response, Error> {
+    if link.settings.flush_before_send {
+        link.port.clear(serialport::ClearBuffer::All).ok();
+    }
+    thread::sleep(Duration::from_millis(link.settings.inter_frame_gap_ms));
 
     let command = Command::new(code, [0, 0, 0, 0], destination, data);
+    let source = command.source;
 
     let mut buffer: [u8; 256] = [0; 256];
-    let len = command.serialize(&mut buffer);
+    let len = command
+        .serialize(&mut buffer)
+        .map_err(|e| Error::InvalidCommand(e.to_string()))?;
+
+    if link.rs485_rts {
+        link.port.write_request_to_send(true)?;
+    }
+    link.port.write_all(&buffer[0..len])?;
+    if link.rs485_rts {
+        // Release the line only once the last byte has left the UART
+        link.port.flush()?;
+        link.port.write_request_to_send(false)?;
+    }
+    link.capture(Direction::Tx, &buffer[0..len]);
+    link.record(Frame {
+        timestamp: SystemTime::now(),
+        direction: Direction::Tx,
+        code,
+        source,
+        destination,
+        payload: data.to_vec(),
+        raw: buffer[0..len].to_vec(),
+        error: None,
+        round_trip: None,
+    });
 
-    port.write(&buffer[0..len])
-        .map_err(|_| String::from("Errore sulla porta"))?;
+    // Started once recorded, the monitor may wait for the view to release it
     let now = Instant::now();
-    let expected_len = expected_response_len(code) as usize;
+    let timeout = link.settings.timeout(code);
+
+    let mut decoder = Decoder::new();
+    let mut read_buffer: [u8; 64] = [0; 64];
+    // Everything read for this command, recorded when no valid response arrives
+    let mut received = Vec::new();
+
+    loop {
+        // Taken before reading, so that bytes arrived by the deadline still count
+        let expired = now.elapsed() > timeout;
 
-    while port.bytes_to_read().unwrap() < expected_response_len(code) {
-        if Instant::now().duration_since(now) > Duration::from_millis(200) {
-            return Err(String::from("Timeout!"));
+        loop {
+            let available = link.port.bytes_to_read()? as usize;
+            if available == 0 {
+                break;
+            }
+            let to_read = available.min(read_buffer.len());
+            let read_len = link.port.read(&mut read_buffer[0..to_read])?;
+            decoder.push(&read_buffer[0..read_len]);
+            link.capture(Direction::Rx, &read_buffer[0..read_len]);
+            received.extend_from_slice(&read_buffer[0..read_len]);
         }
-        //log::info!("{}", port.bytes_to_read().unwrap());
+
+        if let Some(resp) = decoder.next_response() {
+            // The response with whatever the decoder skipped before it
+            let consumed = received.len() - decoder.pending();
+            link.record(Frame {
+                timestamp: SystemTime::now(),
+                direction: Direction::Rx,
+                code,
+                source: resp.source,
+                destination: resp.destination,
+                payload: resp.payload().to_vec(),
+                raw: received[0..consumed].to_vec(),
+                error: None,
+                round_trip: Some(now.elapsed()),
+            });
+
+            return if resp.is_ok() {
+                Ok(resp)
+            } else {
+                Err(Error::Device {
+                    code,
+                    destination,
+                    status: resp.status,
+                })
+            };
+        }
+
+        if expired {
+            let pending = decoder.pending();
+            let frame_error = match decoder.take_error() {
+                _ if pending > 0 => Some(FrameError::BadLength(pending)),
+                error => error,
+            };
+
+            if !received.is_empty() {
+                link.record(Frame {
+                    timestamp: SystemTime::now(),
+                    direction: Direction::Rx,
+                    code,
+                    source: [0; 4],
+                    destination: source,
+                    payload: Vec::new(),
+                    raw: received,
+                    error: frame_error.clone(),
+                    round_trip: Some(now.elapsed()),
+                });
+            }
+
+            return Err(match frame_error {
+                Some(FrameError::BadCrc(_)) => Error::BadCrc { code, destination },
+                Some(FrameError::BadLength(len)) => Error::BadLength {
+                    code,
+                    destination,
+                    len,
+                },
+                Some(FrameError::Malformed(data)) => Error::UnexpectedPayload {
+                    code,
+                    destination,
+                    data,
+                },
+                None => Error::Timeout { code, destination },
+            });
+        }
+
         thread::sleep(Duration::from_millis(10));
     }
-    let mut read_buffer: [u8; 32] = [0; 32];
-    let read_len = port
-        .read(&mut read_buffer[0..expected_len])
-        .map_err(|e| format!("Errore sulla porta: {:?}", e))?;
-
-    if let Some(resp) = Response::parse(&mut read_buffer[0..read_len]) {
-        Ok(resp)
-    } else {
-        Err(String::from(format!(
-            "Risposta non valida ({} byte)",
-            read_len
-        )))
-    }
 }
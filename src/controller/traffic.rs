@@ -0,0 +1,143 @@
+// Frames exchanged on the line, recorded for the traffic monitor
+
+use super::mblp::{Code, FrameError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// Oldest frames are dropped beyond this count
+const MAX_FRAMES: usize = 1000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Tx => write!(f, "TX"),
+            Direction::Rx => write!(f, "RX"),
+        }
+    }
+}
+
+/// Time of day with milliseconds, as frames are timestamped in the monitor and transcripts.
+pub fn time_of_day(timestamp: OffsetDateTime) -> String {
+    let format = format_description!("[hour]:[minute]:[second].[subsecond digits:3]");
+    timestamp.format(format).unwrap_or_default()
+}
+
+/// Bytes in hex separated by spaces, e.g. "01 0A FF".
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    /// Code of the command, also for the response that answers it
+    pub code: Code,
+    pub source: [u8; 4],
+    pub destination: [u8; 4],
+    pub payload: Vec<u8>,
+    /// Bytes as they were on the line, with any garbage read before a response
+    pub raw: Vec<u8>,
+    /// Why the received bytes were not a valid response, if they were not
+    pub error: Option<FrameError>,
+    /// Time from the end of the command to the response
+    pub round_trip: Option<Duration>,
+}
+
+impl Frame {
+    /// True if either end of the frame is `address`.
+    pub fn involves(&self, address: u32) -> bool {
+        let address = address.to_be_bytes();
+        self.source == address || self.destination == address
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {:?} {:08X} -> {:08X} [{}]",
+            time_of_day(OffsetDateTime::from(self.timestamp)),
+            self.direction,
+            self.code,
+            u32::from_be_bytes(self.source),
+            u32::from_be_bytes(self.destination),
+            to_hex(&self.payload)
+        )?;
+
+        match &self.error {
+            None => write!(f, " CRC ok")?,
+            Some(FrameError::BadCrc(_)) => write!(f, " CRC errato")?,
+            Some(FrameError::BadLength(len)) => write!(f, " lunghezza errata ({})", len)?,
+            Some(FrameError::Malformed(_)) => write!(f, " pacchetto non valido")?,
+        }
+        if let Some(round_trip) = self.round_trip {
+            write!(f, " {} ms", round_trip.as_millis())?;
+        }
+
+        write!(f, "\n    {}", to_hex(&self.raw))
+    }
+}
+
+/// Frames shown by the monitor, selected by code and address.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub code: Option<u16>,
+    pub address: Option<u32>,
+}
+
+impl Filter {
+    pub fn matches(&self, frame: &Frame) -> bool {
+        self.code.is_none_or(|code| u16::from(frame.code) == code)
+            && self.address.is_none_or(|address| frame.involves(address))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Traffic {
+    pub frames: VecDeque<Frame>,
+    /// New frames are discarded while paused
+    pub paused: bool,
+}
+
+impl Traffic {
+    pub fn record(&mut self, frame: Frame) {
+        if self.paused {
+            return;
+        }
+        if self.frames.len() >= MAX_FRAMES {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// Writes the frames selected by `filter` as text, one per entry.
+    pub fn export(&self, path: &Path, filter: &Filter) -> Result<usize, String> {
+        let mut text = String::new();
+        let mut count = 0;
+
+        for frame in self.frames.iter().filter(|frame| filter.matches(frame)) {
+            text.push_str(&frame.to_string());
+            text.push('\n');
+            count += 1;
+        }
+
+        fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(count)
+    }
+}
@@ -1,7 +1,10 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
+mod cli;
 mod controller;
 mod model;
+#[cfg(unix)]
+mod simulator;
 mod view;
 
 use simplelog::*;
@@ -9,17 +12,51 @@ use std::sync::{Arc, Mutex};
 use view::app::App;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let headless = args.first().is_some_and(|arg| cli::is_command(arg));
+    #[cfg(windows)]
+    if headless {
+        attach_console();
+    }
+
     CombinedLogger::init(vec![
         TermLogger::new(
             LevelFilter::Info,
             Config::default(),
-            TerminalMode::Mixed,
+            // Keep stdout clean for the machine-readable output
+            if headless {
+                TerminalMode::Stderr
+            } else {
+                TerminalMode::Mixed
+            },
             ColorChoice::Auto,
         ),
         //WriteLogger::new(LevelFilter::Info, Config::default(), File::create("my_rust_binary.log").unwrap()),
     ])
     .unwrap();
 
+    if headless {
+        std::process::exit(cli::run(&args));
+    }
+
+    let simulated_port = match args.first().map(String::as_str) {
+        #[cfg(unix)]
+        Some("--simulate") => match start_simulator(&args[1..]) {
+            Ok(port) => Some(port),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(2);
+            }
+        },
+        // The simulator serves the board on a pseudo-terminal
+        #[cfg(not(unix))]
+        Some("--simulate") => {
+            log::error!("--simulate is only available on Unix systems");
+            std::process::exit(2);
+        }
+        _ => None,
+    };
+
     let model = Arc::new(Mutex::new(model::Model::default()));
 
     let controller_model = Arc::clone(&model);
@@ -35,10 +72,45 @@ fn main() {
         "My egui App",
         options,
         Box::new(|cc| {
-            let controller = controller::Controller::new(controller_model, cc.egui_ctx.clone());
+            let mut controller = controller::Controller::new(controller_model, cc.egui_ctx.clone());
+            if let Some(port) = simulated_port {
+                controller.add_virtual_port(port);
+            }
             let tx = controller.get_command_channel();
             controller.start();
             Box::new(App::new(view_model, tx))
         }),
     );
 }
+
+#[cfg(unix)]
+fn start_simulator(args: &[String]) -> Result<String, String> {
+    let faults = simulator::Faults::from_args(args)?;
+    let address = u32::from_str_radix(model::DEFAULT_ADDRESS, 16).unwrap();
+    let port = simulator::Simulator::new(address.to_be_bytes(), faults).start()?;
+    log::info!("Simulated device on {}", port);
+    Ok(port)
+}
+
+/// Release builds start without a console on Windows, so the output of the
+/// commands would be lost: use the console of the shell they are run from.
+/// Output redirected to a file or a pipe is left as it is.
+#[cfg(windows)]
+fn attach_console() {
+    use std::ffi::c_void;
+
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+        fn GetStdHandle(std_handle: u32) -> *mut c_void;
+    }
+
+    unsafe {
+        if GetStdHandle(STD_OUTPUT_HANDLE).is_null() {
+            AttachConsole(ATTACH_PARENT_PROCESS);
+        }
+    }
+}
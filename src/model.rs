@@ -1,6 +1,19 @@
+use crate::controller::console::Console;
+use crate::controller::device::{RelayFault, RelayResult, StepResult, TestOutcome};
+use crate::controller::job::JobState;
+use crate::controller::manual::Manual;
+use crate::controller::mblp::{CpuId, FwVersion};
+use crate::controller::plan::TestPlan;
+use crate::controller::traffic::Traffic;
 use heapless::Deque as SDeque;
-use std::time::SystemTime;
-use time::{format_description, OffsetDateTime};
+use serde::{Deserialize, Serialize};
+use serialport::{DataBits, FlowControl, Parity, StopBits};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use time::macros::format_description;
+use time::OffsetDateTime;
 
 pub const DEFAULT_ADDRESS: &str = "14030100";
 
@@ -8,49 +21,140 @@ pub const DEFAULT_ADDRESS: &str = "14030100";
 pub enum Connection {
     Connected(String),
     Disconnected,
+    /// The port disappeared while connected, e.g. the adapter was unplugged
+    Lost(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// Raise RTS while transmitting, for RS-485 adapters without automatic direction
+    pub rs485_rts: bool,
+}
+
+impl Default for PortSettings {
+    fn default() -> Self {
+        PortSettings {
+            baud_rate: 9600,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            rs485_rts: false,
+        }
+    }
+}
+
+/// The last test started from the view, updated step by step while it runs
+#[derive(Clone)]
+pub struct TestRun {
+    pub address: u32,
+    pub plan: String,
+    pub steps: Vec<StepResult>,
+    pub relays: Vec<RelayResult>,
+    pub faults: Vec<RelayFault>,
+    /// Total time, once the test is over
+    pub duration: Option<Duration>,
+}
+
+impl TestRun {
+    pub fn new(address: u32, plan: &TestPlan) -> Self {
+        let outcome = TestOutcome::new(plan);
+        TestRun {
+            address,
+            plan: plan.name.clone(),
+            steps: outcome.steps,
+            relays: outcome.relays,
+            faults: outcome.faults,
+            duration: None,
+        }
+    }
+}
+
+/// A board that answered during a bus scan
+#[derive(Clone)]
+pub struct DeviceInfo {
+    pub address: u32,
+    pub version: Option<FwVersion>,
 }
 
 #[derive(Clone)]
 pub struct Model {
     pub ports: Vec<String>,
+    /// Last settings used on each port, by name
+    pub port_settings: BTreeMap<String, PortSettings>,
     pub connection: Connection,
+    /// Reconnect to a lost port as soon as it is available again
+    pub auto_reconnect: bool,
     pub messages: SDeque<String, 8>,
-    pub version: Option<(u8, u8, u8)>,
+    pub version: Option<FwVersion>,
+    pub cpu_id: Option<CpuId>,
     pub device_address: String,
+    pub devices: Vec<DeviceInfo>,
+    /// Next number from the serial allocator, or why it is not available
+    pub next_serial: Result<u32, String>,
+    /// Locked on its own, so the frames are not copied with the rest of the model
+    pub traffic: Arc<Mutex<Traffic>>,
+    /// File receiving the raw bytes of the connection, if capturing
+    pub capture: Option<PathBuf>,
+    pub console: Console,
+    /// Operation running in the background, if any
+    pub job: Option<JobState>,
+    pub test_run: Option<TestRun>,
+    /// Tests go on after a failed step, whatever their plan says
+    pub run_all: bool,
+    pub manual: Manual,
 }
 
 impl Default for Model {
     fn default() -> Self {
         Model {
             ports: Vec::new(),
+            port_settings: BTreeMap::new(),
             connection: Connection::Disconnected,
+            auto_reconnect: true,
             messages: SDeque::default(),
             version: None,
+            cpu_id: None,
             device_address: String::from(DEFAULT_ADDRESS),
+            devices: Vec::new(),
+            next_serial: Err(String::new()),
+            traffic: Arc::new(Mutex::new(Traffic::default())),
+            capture: None,
+            console: Console::default(),
+            job: None,
+            test_run: None,
+            run_all: false,
+            manual: Manual::default(),
         }
     }
 }
 
 impl Model {
-    pub fn is_connected(self: &Self) -> bool {
+    pub fn is_connected(&self) -> bool {
         match self.connection {
             Connection::Connected(_) => true,
-            Connection::Disconnected => false,
+            Connection::Disconnected | Connection::Lost(_) => false,
         }
     }
 
-    pub fn message(self: &mut Self, msg: String) {
+    pub fn message(&mut self, msg: String) {
         if self.messages.is_full() {
             self.messages.pop_front();
         }
 
-        let format = format_description::parse("[hour]:[minute]:[second]").unwrap();
+        let format = format_description!("[hour]:[minute]:[second]");
 
         self.messages
             .push_back(format!(
                 "[{:<8}] {}",
                 OffsetDateTime::from(SystemTime::now())
-                    .format(&format)
+                    .format(format)
                     .unwrap(),
                 msg
             ))
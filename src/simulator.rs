@@ -0,0 +1,388 @@
+// Simulated Bio minion board, answering MBLP commands on a pseudo-terminal
+
+use crate::controller::mblp::{
+    Code, Command, CpuId, Decoder, DeviceAddress, FwVersion, InputState, OutputCommand, Payload,
+    Response,
+};
+use serialport::{SerialPort, TTYPort};
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+
+pub const RELAYS: usize = 4;
+
+/// Reported to `ProbeId`
+pub const CPU_ID: CpuId = CpuId(0x3A1F_77C2);
+
+#[derive(Clone, Debug)]
+pub struct Faults {
+    /// Relay whose contact never closes, so its input bit stays low
+    pub stuck_relay: Option<u8>,
+    /// Relay that also raises the input bit of another one, as (relay, input)
+    pub crosstalk: Option<(u8, u8)>,
+    /// Version reported to `ReadFWVersion`
+    pub firmware: FwVersion,
+    /// Corrupt the CRC of every n-th response
+    pub bad_crc_every: Option<u32>,
+    /// Acknowledge `SetAddress` without changing the address
+    pub ignore_set_address: bool,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            stuck_relay: None,
+            crosstalk: None,
+            firmware: FwVersion {
+                major: 1,
+                minor: 0,
+                patch: 0,
+            },
+            bad_crc_every: None,
+            ignore_set_address: false,
+        }
+    }
+}
+
+impl Faults {
+    /// Parses the fault options following `--simulate` on the command line.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut faults = Faults::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .cloned()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+
+            match arg.as_str() {
+                "--stuck-relay" => {
+                    let relay = value()?;
+                    faults.stuck_relay = Some(
+                        relay
+                            .parse()
+                            .map_err(|_| format!("Invalid relay: {}", relay))?,
+                    );
+                }
+                "--crosstalk" => {
+                    let pair = value()?;
+                    faults.crosstalk = Some(
+                        pair.split_once(':')
+                            .and_then(|(relay, input)| {
+                                Some((relay.parse().ok()?, input.parse().ok()?))
+                            })
+                            .filter(|&(_, input)| input < 8)
+                            .ok_or_else(|| {
+                                format!("Invalid crosstalk, expected relay:input: {}", pair)
+                            })?,
+                    );
+                }
+                "--fw" => {
+                    let version = value()?;
+                    faults.firmware = version
+                        .parse()
+                        .map_err(|_| format!("Invalid firmware version: {}", version))?;
+                }
+                "--bad-crc" => {
+                    let every = value()?;
+                    faults.bad_crc_every = Some(
+                        every
+                            .parse()
+                            .ok()
+                            .filter(|&n| n > 0)
+                            .ok_or_else(|| format!("Invalid CRC fault period: {}", every))?,
+                    );
+                }
+                "--ignore-set-address" => faults.ignore_set_address = true,
+                _ => return Err(format!("Unknown simulator option: {}", arg)),
+            }
+        }
+
+        Ok(faults)
+    }
+}
+
+pub struct Simulator {
+    address: [u8; 4],
+    relays: [bool; RELAYS],
+    faults: Faults,
+    responses: u32,
+}
+
+impl Simulator {
+    pub fn new(address: [u8; 4], faults: Faults) -> Self {
+        Simulator {
+            address,
+            relays: [false; RELAYS],
+            faults,
+            responses: 0,
+        }
+    }
+
+    /// Opens a pseudo-terminal and serves it from a background thread.
+    /// Returns the name of the port the application should connect to.
+    pub fn start(self) -> Result<String, String> {
+        let (master, slave) =
+            TTYPort::pair().map_err(|e| format!("Cannot open pseudo-terminal: {}", e))?;
+        let name = slave
+            .name()
+            .ok_or_else(|| String::from("Pseudo-terminal has no name"))?;
+
+        thread::spawn(move || {
+            // Keep the slave end open so the master does not see a hang-up
+            // while the application is not connected.
+            let _slave = slave;
+            self.serve(master);
+        });
+
+        Ok(name)
+    }
+
+    fn serve(mut self, mut port: TTYPort) {
+        let mut decoder = Decoder::new();
+        let mut read_buffer: [u8; 64] = [0; 64];
+
+        loop {
+            match port.read(&mut read_buffer) {
+                Ok(len) => decoder.push(&read_buffer[0..len]),
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    log::error!("Simulator port error: {:?}", e);
+                    return;
+                }
+            }
+
+            while let Some(command) = decoder.next_command() {
+                log::debug!("Simulator received {}", command);
+                if let Some(response) = self.handle(&command) {
+                    let mut buffer: [u8; 256] = [0; 256];
+                    let len = match response.serialize(&mut buffer) {
+                        Ok(len) => len,
+                        Err(e) => {
+                            log::error!("Simulator response not sent: {}", e);
+                            continue;
+                        }
+                    };
+
+                    self.responses += 1;
+                    if let Some(every) = self.faults.bad_crc_every {
+                        if self.responses.is_multiple_of(every) {
+                            buffer[len - 1] = buffer[len - 1].wrapping_add(1);
+                        }
+                    }
+
+                    if let Err(e) = port.write_all(&buffer[0..len]) {
+                        log::error!("Simulator port error: {:?}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn inputs(&self) -> u8 {
+        self.relays
+            .iter()
+            .enumerate()
+            .filter(|&(i, &on)| on && self.faults.stuck_relay != Some(i as u8))
+            .fold(0, |mask, (i, _)| {
+                let crosstalk = match self.faults.crosstalk {
+                    Some((relay, input)) if relay as usize == i => 1 << input,
+                    _ => 0,
+                };
+                mask | (1 << i) | crosstalk
+            })
+    }
+
+    fn handle(&mut self, command: &Command) -> Option<Response> {
+        let data = command.payload();
+        let broadcast = command.destination == [0; 4];
+
+        // The address can be assigned regardless of the current one
+        if command.destination != self.address && !broadcast && command.code != Code::SetAddress {
+            return None;
+        }
+
+        let source = command.source;
+
+        match command.code {
+            Code::ReadInput => Some(Response::ok(
+                source,
+                self.address,
+                &InputState(self.inputs()).encode(),
+            )),
+            Code::SetOutput => match OutputCommand::decode(data) {
+                Ok(OutputCommand { relay, on }) if (relay as usize) < RELAYS => {
+                    self.relays[relay as usize] = on;
+                    Some(Response::ok(source, self.address, &[]))
+                }
+                _ => Some(Response::err(source, self.address, &[])),
+            },
+            Code::SetAddress => match DeviceAddress::decode(data) {
+                Ok(DeviceAddress(address)) => {
+                    if !self.faults.ignore_set_address {
+                        self.address = address.to_be_bytes();
+                    }
+                    log::info!("Simulator address set to {:02X?}", self.address);
+                    Some(Response::ok(source, self.address, &[0]))
+                }
+                _ => Some(Response::err(source, self.address, &[])),
+            },
+            Code::ReadAddress => Some(Response::ok(source, self.address, &self.address)),
+            Code::ReadFWVersion => Some(Response::ok(
+                source,
+                self.address,
+                &self.faults.firmware.encode(),
+            )),
+            Code::ProbeId => Some(Response::cpu_id(source, CPU_ID.0.to_be_bytes())),
+            Code::Unknown(_, _) => Some(Response::err(source, self.address, &[])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::console::{self, RawRequest};
+    use crate::controller::device::{self, Port, RelayResult, TestOutcome};
+    use crate::controller::error::Error;
+    use crate::controller::job::Unobserved;
+    use crate::controller::plan::{Action, PlanStep, TestPlan};
+    use crate::controller::serial::{Link, TransportSettings};
+    use crate::model::PortSettings;
+
+    const ADDRESS: [u8; 4] = [0x14, 0x03, 0x01, 0x00];
+
+    fn connect(faults: Faults) -> Port {
+        let name = Simulator::new(ADDRESS, faults).start().unwrap();
+        Link::open(
+            &name,
+            &PortSettings::default(),
+            TransportSettings::default(),
+        )
+        .unwrap()
+    }
+
+    fn run(faults: Faults, plan: &TestPlan) -> TestOutcome {
+        device::test_device(&mut connect(faults), ADDRESS, plan, &mut Unobserved)
+    }
+
+    fn run_all() -> TestPlan {
+        TestPlan {
+            run_all: true,
+            ..TestPlan::default()
+        }
+    }
+
+    #[test]
+    fn healthy_board_passes() {
+        let outcome = run(Faults::default(), &TestPlan::default());
+        assert!(outcome.result().is_ok());
+        assert_eq!(outcome.relays, vec![RelayResult::Pass; RELAYS]);
+    }
+
+    #[test]
+    fn stuck_relay() {
+        let faults = Faults {
+            stuck_relay: Some(1),
+            ..Faults::default()
+        };
+        let outcome = run(faults, &run_all());
+
+        assert_eq!(outcome.failures.len(), 1);
+        assert_eq!(outcome.relays[1], RelayResult::Fail);
+        assert_eq!(outcome.faults.len(), 1);
+        assert_eq!(outcome.faults[0].relay, Some(1));
+        assert_eq!(outcome.faults[0].missing, 0b0010);
+        assert_eq!(outcome.faults[0].extra, 0);
+        assert!(matches!(outcome.safe_state, Some(Ok(()))));
+    }
+
+    #[test]
+    fn crosstalk() {
+        let faults = Faults {
+            crosstalk: Some((2, 3)),
+            ..Faults::default()
+        };
+        let outcome = run(faults, &run_all());
+
+        assert_eq!(outcome.relays[2], RelayResult::Fail);
+        assert_eq!(outcome.faults.len(), 1);
+        assert_eq!(outcome.faults[0].relay, Some(2));
+        assert_eq!(outcome.faults[0].missing, 0);
+        assert_eq!(outcome.faults[0].extra, 0b1000);
+    }
+
+    #[test]
+    fn wrong_firmware() {
+        let faults = Faults {
+            firmware: FwVersion {
+                major: 2,
+                minor: 0,
+                patch: 0,
+            },
+            ..Faults::default()
+        };
+        let plan = TestPlan {
+            steps: vec![PlanStep {
+                name: None,
+                action: Action::ExpectFirmware {
+                    min: "1.0.0".parse().unwrap(),
+                    max: "1.255.255".parse().unwrap(),
+                },
+            }],
+            ..TestPlan::default()
+        };
+        let outcome = run(faults, &plan);
+
+        let failure = outcome.result().unwrap_err();
+        assert!(matches!(failure.error, Error::FirmwareMismatch { .. }));
+    }
+
+    #[test]
+    fn bad_crc_is_retried() {
+        let faults = Faults {
+            bad_crc_every: Some(3),
+            ..Faults::default()
+        };
+        assert!(run(faults, &TestPlan::default()).result().is_ok());
+    }
+
+    #[test]
+    fn bad_crc_on_every_response() {
+        let faults = Faults {
+            bad_crc_every: Some(1),
+            ..Faults::default()
+        };
+        let outcome = run(faults, &TestPlan::default());
+
+        let failure = outcome.result().unwrap_err();
+        assert!(matches!(failure.error, Error::BadCrc { .. }));
+    }
+
+    #[test]
+    fn ignored_set_address() {
+        let faults = Faults {
+            ignore_set_address: true,
+            ..Faults::default()
+        };
+        let mut port = connect(faults);
+
+        let result = device::set_serial_number(&mut port, [0x14, 0x03, 0x01, 0x07]);
+        assert!(matches!(result, Err(Error::NotVerified { .. })));
+    }
+
+    #[test]
+    fn console_probe() {
+        let request = RawRequest {
+            destination: u32::from_be_bytes(ADDRESS),
+            code: 0x0000,
+            payload: Vec::new(),
+            expected_len: Some(4),
+            probe: true,
+        };
+        let response = console::send(&mut connect(Faults::default()), &request).unwrap();
+        assert_eq!(response.payload(), CPU_ID.0.to_be_bytes());
+    }
+}
@@ -1,25 +1,75 @@
 use super::Message;
-use crate::model::{Connection, Model, DEFAULT_ADDRESS};
+use crate::controller::console::{self, Favorite, RawRequest};
+use crate::controller::device::{InputCheck, RelayResult, StepStatus};
+use crate::controller::job::JobState;
+use crate::controller::manual::{self, ChannelStatus};
+use crate::controller::mblp::{self, Code};
+use crate::controller::traffic::{self, Filter};
+use crate::model::{Connection, Model, PortSettings, TestRun, DEFAULT_ADDRESS};
 use egui::Layout;
+use serialport::{DataBits, FlowControl, Parity, StopBits};
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub struct App {
     model: Arc<Mutex<Model>>,
     selected_port: String,
+    port_settings: PortSettings,
     valid_device_address: String,
     device_address: String,
+    scan_addresses: String,
+    show_traffic: bool,
+    traffic_code: String,
+    traffic_address: String,
+    traffic_file: String,
+    capture_file: String,
+    show_console: bool,
+    console_destination: String,
+    console_code: String,
+    console_payload: String,
+    console_expected_len: String,
+    favorite_name: String,
+    show_manual: bool,
+    manual_period_ms: u64,
+    /// Board and period last asked to the controller, `None` if not polling
+    manual_polling: Option<(u32, u64)>,
     controller: mpsc::Sender<Message>,
 }
 
+/// Longest wait for the controller when the window closes
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest number of addresses probed by a single scan
+const MAX_SCAN_ADDRESSES: usize = 1024;
+
+const BAUD_RATES: [u32; 8] = [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
+
 impl App {
     pub fn new(model: Arc<Mutex<Model>>, controller: mpsc::Sender<Message>) -> Self {
         Self {
             model,
             controller,
             selected_port: String::new(),
+            port_settings: PortSettings::default(),
             device_address: String::from(DEFAULT_ADDRESS),
             valid_device_address: String::from(DEFAULT_ADDRESS),
+            scan_addresses: format!("{}-{:08X}", DEFAULT_ADDRESS, default_address() + 0x0F),
+            show_traffic: false,
+            traffic_code: String::new(),
+            traffic_address: String::new(),
+            traffic_file: String::from("traffico.txt"),
+            capture_file: String::from("cattura.txt"),
+            show_console: false,
+            console_destination: String::from(DEFAULT_ADDRESS),
+            console_code: String::new(),
+            console_payload: String::new(),
+            console_expected_len: String::new(),
+            favorite_name: String::new(),
+            show_manual: false,
+            manual_period_ms: manual::DEFAULT_POLL_MS,
+            manual_polling: None,
         }
     }
 }
@@ -42,28 +92,86 @@ impl eframe::App for App {
                         .selected_text(self.selected_port.as_str())
                         .show_ui(ui, |ui| {
                             for port in &model.ports {
-                                ui.selectable_value(
+                                let selected = ui.selectable_value(
                                     &mut self.selected_port,
                                     port.clone(),
                                     port.clone(),
                                 );
+                                if selected.clicked() {
+                                    // Start from the settings last used on this port
+                                    self.port_settings =
+                                        model.port_settings.get(port).cloned().unwrap_or_default();
+                                }
                             }
                         });
-                    if ui.button("Connetti").clicked() {
+                    if model.is_connected() {
+                        if ui.button("Disconnetti").clicked() {
+                            self.controller.send(Message::Disconnect).ok();
+                        }
+                    } else if ui.button("Connetti").clicked() {
+                        self.controller
+                            .send(Message::ConnectToPort(
+                                self.selected_port.clone(),
+                                self.port_settings.clone(),
+                            ))
+                            .ok();
+                    }
+                    let mut auto_reconnect = model.auto_reconnect;
+                    if ui.checkbox(&mut auto_reconnect, "Riconnetti").changed() {
                         self.controller
-                            .send(Message::ConnectToPort(self.selected_port.clone()))
+                            .send(Message::AutoReconnect(auto_reconnect))
                             .ok();
                     }
+                    ui.checkbox(&mut self.show_traffic, "Traffico");
+                    ui.checkbox(&mut self.show_console, "Console");
+                    ui.checkbox(&mut self.show_manual, "I/O manuale");
                     ui.with_layout(Layout::right_to_left(), |ui| {
                         ui.label(match &model.connection {
                             Connection::Connected(port) => format!("Connesso a {}", port),
                             Connection::Disconnected => "Disconnesso".into(),
+                            Connection::Lost(port) => format!("{} scollegata", port),
                         });
                     });
                 });
+
+                egui::CollapsingHeader::new("Impostazioni porta").show(ui, |ui| {
+                    self.port_settings_panel(ui);
+                });
             });
         });
 
+        let mut show_traffic = self.show_traffic;
+        egui::Window::new("Traffico")
+            .open(&mut show_traffic)
+            .default_width(640.)
+            .show(ctx, |ui| self.traffic_panel(ui, &model));
+        self.show_traffic = show_traffic;
+
+        let mut show_console = self.show_console;
+        egui::Window::new("Console")
+            .open(&mut show_console)
+            .default_width(480.)
+            .show(ctx, |ui| self.console_panel(ui, &model));
+        self.show_console = show_console;
+
+        let mut show_manual = self.show_manual;
+        egui::Window::new("I/O manuale")
+            .open(&mut show_manual)
+            .show(ctx, |ui| self.manual_panel(ui, &model));
+        self.show_manual = show_manual;
+
+        // Poll only while the panel is open on a connected board
+        let polling = match destination {
+            Ok(address) if self.show_manual && model.is_connected() => {
+                Some((address, self.manual_period_ms))
+            }
+            _ => None,
+        };
+        if polling != self.manual_polling {
+            self.controller.send(Message::ManualIo(polling)).ok();
+            self.manual_polling = polling;
+        }
+
         egui::TopBottomPanel::bottom(1)
             .default_height(128.)
             .show(ctx, |ui| {
@@ -84,7 +192,7 @@ impl eframe::App for App {
             if model.is_connected() {
                 ui.horizontal(|ui| {
                     ui.add(egui::Label::new("Matricola"));
-                    self.device_address = model.device_address;
+                    self.device_address = model.device_address.clone();
                     if ui.text_edit_singleline(&mut self.device_address).changed() {
                         let next_value = Message::DeviceAddress(self.manage_address_input());
                         self.controller.send(next_value).ok();
@@ -103,11 +211,24 @@ impl eframe::App for App {
                     }
                 });
 
+                ui.horizontal(|ui| {
+                    let (label, hint) = match &model.next_serial {
+                        Ok(serial) => (format!("Assegna {:08X}", serial), String::new()),
+                        Err(e) => (String::from("Assegna"), e.clone()),
+                    };
+                    let button = ui
+                        .add_enabled(model.next_serial.is_ok(), egui::Button::new(label))
+                        .on_disabled_hover_text(hint);
+                    if button.clicked() {
+                        self.controller.send(Message::AssignSerialNumber).ok();
+                    }
+                });
+
                 ui.horizontal(|ui| {
                     ui.add(egui::Label::new(format!(
                         "Versione firmware: {}",
-                        if let Some((fw1, fw2, fw3)) = model.version {
-                            format!("{}.{}.{}", fw1, fw2, fw3)
+                        if let Some(version) = model.version {
+                            version.to_string()
                         } else {
                             "assente".into()
                         }
@@ -119,25 +240,483 @@ impl eframe::App for App {
                     }
                 });
 
-                ui.add_enabled_ui(self.is_address_valid(), |ui| {
-                    if ui.button("Collauda").clicked() {
+                ui.horizontal(|ui| {
+                    ui.add(egui::Label::new(format!(
+                        "ID CPU: {}",
+                        if let Some(cpu_id) = model.cpu_id {
+                            cpu_id.to_string()
+                        } else {
+                            "assente".into()
+                        }
+                    )));
+                    if ui.add(egui::Button::new("Leggi")).clicked() {
                         self.controller
-                            .send(Message::Test(destination.clone().unwrap()))
+                            .send(Message::ReadCpuId(destination.clone().unwrap()))
                             .ok();
                     }
                 });
+
+                ui.horizontal(|ui| {
+                    ui.add_enabled_ui(self.is_address_valid() && model.job.is_none(), |ui| {
+                        if ui.button("Collauda").clicked() {
+                            self.controller
+                                .send(Message::Test(destination.clone().unwrap()))
+                                .ok();
+                        }
+                    });
+                    let mut run_all = model.run_all;
+                    if ui.checkbox(&mut run_all, "Tutti i passi").changed() {
+                        self.controller.send(Message::RunAllSteps(run_all)).ok();
+                    }
+                    if let Some(job) = &model.job {
+                        self.job_progress(ui, job);
+                    }
+                });
+
+                if let Some(run) = &model.test_run {
+                    test_checklist(ui, run);
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    ui.add(egui::Label::new("Indirizzi"));
+                    ui.text_edit_singleline(&mut self.scan_addresses)
+                        .on_hover_text(
+                            "Elenco separato da virgole, es. 14030100-1403010F, 14030200",
+                        );
+                    let addresses = parse_addresses(self.scan_addresses.as_str());
+                    ui.add_enabled_ui(addresses.is_ok() && model.job.is_none(), |ui| {
+                        if ui.button("Cerca").clicked() {
+                            if let Ok(addresses) = addresses {
+                                self.controller.send(Message::Scan(addresses)).ok();
+                            }
+                        }
+                    });
+                });
+
+                egui::ScrollArea::vertical()
+                    .id_source("devices")
+                    .max_height(96.)
+                    .show(ui, |ui| {
+                        for device in &model.devices {
+                            let address = format!("{:08X}", device.address);
+                            let label = format!(
+                                "{}  firmware {}",
+                                address,
+                                if let Some(version) = device.version {
+                                    version.to_string()
+                                } else {
+                                    "assente".into()
+                                }
+                            );
+                            let selected = model.device_address == address;
+                            if ui.selectable_label(selected, label).clicked() {
+                                self.controller.send(Message::DeviceAddress(address)).ok();
+                            }
+                        }
+                    });
             }
         });
     }
+    fn on_exit(&mut self, _gl: &eframe::glow::Context) {
+        // Give a running test the time to switch its relays off
+        let (done, wait) = mpsc::channel();
+        if self.controller.send(Message::Shutdown(done)).is_ok()
+            && wait.recv_timeout(SHUTDOWN_TIMEOUT).is_err()
+        {
+            log::error!("Controller did not stop in time, relays may still be on");
+        }
+    }
 }
 
 impl App {
-    fn is_address_valid(self: &Self) -> bool {
+    fn manual_panel(&mut self, ui: &mut egui::Ui, model: &Model) {
+        let manual = &model.manual;
+
+        ui.horizontal(|ui| {
+            ui.label(format!("Scheda {:08X}", manual.address));
+            ui.label("Lettura ogni");
+            ui.add(
+                egui::DragValue::new(&mut self.manual_period_ms)
+                    .clamp_range(manual::MIN_POLL_MS..=5000)
+                    .suffix(" ms"),
+            );
+        });
+
+        ui.add_enabled_ui(model.job.is_none(), |ui| {
+            egui::Grid::new("manual_relays").show(ui, |ui| {
+                for (index, relay) in manual.relays.iter().enumerate() {
+                    let mut on = relay.on;
+                    if ui.checkbox(&mut on, format!("Rele {}", index)).changed() {
+                        self.controller
+                            .send(Message::SetRelay(index as u8, on))
+                            .ok();
+                    }
+                    channel_status(ui, &relay.status);
+                    ui.end_row();
+                }
+            });
+        });
+
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Ingressi");
+            for input in (0..8).rev() {
+                let color = match manual.inputs {
+                    Some(inputs) if inputs.is_on(input) => egui::Color32::GREEN,
+                    Some(_) => egui::Color32::DARK_GRAY,
+                    None => egui::Color32::GRAY,
+                };
+                ui.colored_label(color, input.to_string());
+            }
+            channel_status(ui, &manual.input_status);
+        });
+    }
+
+    fn job_progress(&mut self, ui: &mut egui::Ui, job: &JobState) {
+        let progress = &job.progress;
+        let mut text = format!("{}: {}", job.name, progress.step);
+        if let Some(relay) = progress.relay {
+            text.push_str(&format!(" (rele' {})", relay));
+        }
+        if job.stopping {
+            text.push_str(" - arresto in corso");
+        }
+
+        ui.add_enabled_ui(!job.stopping, |ui| {
+            if ui.button("Stop").clicked() {
+                self.controller.send(Message::Stop).ok();
+            }
+        });
+        ui.add(egui::ProgressBar::new(progress.percent as f32 / 100.).text(text));
+    }
+
+    fn port_settings_panel(&mut self, ui: &mut egui::Ui) {
+        let settings = &mut self.port_settings;
+
+        egui::Grid::new("port_settings").show(ui, |ui| {
+            ui.label("Baud");
+            egui::ComboBox::from_id_source("baud_rate")
+                .selected_text(settings.baud_rate.to_string())
+                .show_ui(ui, |ui| {
+                    for baud_rate in BAUD_RATES {
+                        ui.selectable_value(
+                            &mut settings.baud_rate,
+                            baud_rate,
+                            baud_rate.to_string(),
+                        );
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Bit di dati");
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut settings.data_bits, DataBits::Seven, "7");
+                ui.selectable_value(&mut settings.data_bits, DataBits::Eight, "8");
+            });
+            ui.end_row();
+
+            ui.label("Parita'");
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut settings.parity, Parity::None, "Nessuna");
+                ui.selectable_value(&mut settings.parity, Parity::Even, "Pari");
+                ui.selectable_value(&mut settings.parity, Parity::Odd, "Dispari");
+            });
+            ui.end_row();
+
+            ui.label("Bit di stop");
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut settings.stop_bits, StopBits::One, "1");
+                ui.selectable_value(&mut settings.stop_bits, StopBits::Two, "2");
+            });
+            ui.end_row();
+
+            ui.label("Controllo di flusso");
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut settings.flow_control, FlowControl::None, "Nessuno");
+                ui.selectable_value(
+                    &mut settings.flow_control,
+                    FlowControl::Software,
+                    "XON/XOFF",
+                );
+                ui.selectable_value(&mut settings.flow_control, FlowControl::Hardware, "RTS/CTS");
+            });
+            ui.end_row();
+
+            ui.label("RS-485");
+            ui.checkbox(&mut settings.rs485_rts, "Direzione con RTS");
+            ui.end_row();
+        });
+    }
+
+    fn traffic_panel(&mut self, ui: &mut egui::Ui, model: &Model) {
+        // Held while drawing, the frames are too many to copy at every repaint;
+        // only the visible ones are formatted
+        let traffic = model.traffic.lock().unwrap();
+
+        ui.horizontal(|ui| {
+            ui.label("Codice");
+            ui.add(egui::TextEdit::singleline(&mut self.traffic_code).desired_width(48.));
+            ui.label("Indirizzo");
+            ui.add(egui::TextEdit::singleline(&mut self.traffic_address).desired_width(72.));
+
+            let mut paused = traffic.paused;
+            if ui.checkbox(&mut paused, "Pausa").changed() {
+                self.controller.send(Message::PauseTraffic(paused)).ok();
+            }
+            if ui.button("Pulisci").clicked() {
+                self.controller.send(Message::ClearTraffic).ok();
+            }
+        });
+
+        let filter = self.traffic_filter();
+
+        ui.horizontal(|ui| {
+            ui.label("File");
+            ui.text_edit_singleline(&mut self.traffic_file);
+            ui.add_enabled_ui(filter.is_ok() && !self.traffic_file.is_empty(), |ui| {
+                if ui.button("Esporta").clicked() {
+                    if let Ok(filter) = &filter {
+                        self.controller
+                            .send(Message::ExportTraffic(
+                                PathBuf::from(&self.traffic_file),
+                                filter.clone(),
+                            ))
+                            .ok();
+                    }
+                }
+            });
+        });
+
+        ui.horizontal(|ui| match &model.capture {
+            Some(path) => {
+                ui.label(format!("Cattura su {}", path.display()));
+                if ui.button("Ferma cattura").clicked() {
+                    self.controller.send(Message::Capture(None)).ok();
+                }
+            }
+            None => {
+                ui.label("Cattura");
+                ui.text_edit_singleline(&mut self.capture_file);
+                ui.add_enabled_ui(!self.capture_file.is_empty(), |ui| {
+                    if ui.button("Avvia cattura").clicked() {
+                        self.controller
+                            .send(Message::Capture(Some(PathBuf::from(&self.capture_file))))
+                            .ok();
+                    }
+                });
+            }
+        });
+
+        let filter = match filter {
+            Ok(filter) => filter,
+            Err(e) => {
+                ui.colored_label(egui::Color32::RED, e);
+                Filter::default()
+            }
+        };
+
+        ui.separator();
+
+        let frames: Vec<_> = traffic
+            .frames
+            .iter()
+            .filter(|f| filter.matches(f))
+            .collect();
+        let row_height = 2. * ui.text_style_height(&egui::TextStyle::Monospace);
+
+        egui::ScrollArea::vertical()
+            .id_source("traffic")
+            .stick_to_bottom()
+            .auto_shrink([false, false])
+            .show_rows(ui, row_height, frames.len(), |ui, rows| {
+                for frame in &frames[rows] {
+                    let mut text = egui::RichText::new(frame.to_string()).monospace();
+                    if frame.error.is_some() {
+                        text = text.color(egui::Color32::RED);
+                    }
+                    // Every frame takes two lines, the decoded one and the raw bytes
+                    ui.add(egui::Label::new(text).wrap(false));
+                }
+            });
+    }
+
+    fn console_panel(&mut self, ui: &mut egui::Ui, model: &Model) {
+        egui::Grid::new("console").show(ui, |ui| {
+            ui.label("Destinazione");
+            ui.text_edit_singleline(&mut self.console_destination);
+            ui.end_row();
+
+            ui.label("Codice");
+            ui.text_edit_singleline(&mut self.console_code)
+                .on_hover_text("Esadecimale o nome, es. 400A o read_fw_version");
+            ui.end_row();
+
+            ui.label("Dati");
+            ui.text_edit_singleline(&mut self.console_payload)
+                .on_hover_text("Byte in esadecimale, es. 01 0A FF");
+            ui.end_row();
+
+            ui.label("Byte attesi");
+            ui.text_edit_singleline(&mut self.console_expected_len)
+                .on_hover_text("Vuoto per accettare qualsiasi lunghezza");
+            ui.end_row();
+        });
+
+        let request = self.console_request();
+
+        ui.horizontal(|ui| {
+            ui.add_enabled_ui(request.is_ok() && model.is_connected(), |ui| {
+                if ui.button("Invia").clicked() {
+                    if let Ok(request) = &request {
+                        self.controller.send(Message::SendRaw(request.clone())).ok();
+                    }
+                }
+            });
+            if let Err(e) = &request {
+                ui.colored_label(egui::Color32::RED, e);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Nome");
+            ui.text_edit_singleline(&mut self.favorite_name);
+            let name = self.favorite_name.trim();
+            ui.add_enabled_ui(request.is_ok() && !name.is_empty(), |ui| {
+                if ui.button("Salva preferito").clicked() {
+                    if let Ok(request) = &request {
+                        self.controller
+                            .send(Message::SaveFavorite(Favorite {
+                                name: name.to_string(),
+                                request: request.clone(),
+                            }))
+                            .ok();
+                    }
+                }
+            });
+        });
+
+        egui::CollapsingHeader::new("Preferiti").show(ui, |ui| {
+            for favorite in &model.console.favorites {
+                ui.horizontal(|ui| {
+                    if ui
+                        .button(favorite.name.as_str())
+                        .on_hover_text(favorite.request.to_string())
+                        .clicked()
+                    {
+                        self.load_request(&favorite.request);
+                        self.favorite_name = favorite.name.clone();
+                    }
+                    if ui.small_button("X").clicked() {
+                        self.controller
+                            .send(Message::RemoveFavorite(favorite.name.clone()))
+                            .ok();
+                    }
+                });
+            }
+        });
+
+        ui.separator();
+
+        egui::ScrollArea::vertical()
+            .id_source("console_history")
+            .stick_to_bottom()
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                for entry in &model.console.history {
+                    if ui
+                        .selectable_label(false, entry.request.to_string())
+                        .clicked()
+                    {
+                        self.load_request(&entry.request);
+                    }
+                    match &entry.result {
+                        Ok(response) => ui.monospace(response),
+                        Err(e) => ui.colored_label(egui::Color32::RED, e),
+                    };
+                }
+            });
+    }
+
+    fn console_request(&self) -> Result<RawRequest, String> {
+        let destination = self.console_destination.trim();
+        let code = self.console_code.trim();
+        let expected_len = self.console_expected_len.trim();
+
+        let payload = console::parse_hex(&self.console_payload)?;
+        if payload.len() > console::MAX_PAYLOAD {
+            return Err(format!("Massimo {} byte di dati", console::MAX_PAYLOAD));
+        }
+
+        let spec = mblp::lookup(code);
+        Ok(RawRequest {
+            destination: u32::from_str_radix(destination, 16)
+                .map_err(|_| format!("Destinazione non valida: {}", destination))?,
+            code: match spec {
+                Some(spec) => spec.number,
+                None => u16::from_str_radix(code, 16)
+                    .map_err(|_| format!("Codice non valido: {}", code))?,
+            },
+            probe: spec.is_some_and(|spec| spec.code == Code::ProbeId),
+            payload,
+            expected_len: if expected_len.is_empty() {
+                None
+            } else {
+                Some(
+                    expected_len
+                        .parse()
+                        .map_err(|_| format!("Lunghezza non valida: {}", expected_len))?,
+                )
+            },
+        })
+    }
+
+    fn load_request(&mut self, request: &RawRequest) {
+        self.console_destination = format!("{:08X}", request.destination);
+        self.console_code = if request.probe {
+            String::from("probe_id")
+        } else {
+            format!("{:04X}", request.code)
+        };
+        self.console_payload = traffic::to_hex(&request.payload);
+        self.console_expected_len = request
+            .expected_len
+            .map(|len| len.to_string())
+            .unwrap_or_default();
+    }
+
+    fn traffic_filter(&self) -> Result<Filter, String> {
+        let code = self.traffic_code.trim();
+        let address = self.traffic_address.trim();
+
+        Ok(Filter {
+            code: if code.is_empty() {
+                None
+            } else {
+                Some(
+                    u16::from_str_radix(code, 16)
+                        .map_err(|_| format!("Codice non valido: {}", code))?,
+                )
+            },
+            address: if address.is_empty() {
+                None
+            } else {
+                Some(
+                    u32::from_str_radix(address, 16)
+                        .map_err(|_| format!("Indirizzo non valido: {}", address))?,
+                )
+            },
+        })
+    }
+
+    fn is_address_valid(&self) -> bool {
         u32::from_str_radix(self.device_address.as_str(), 16).is_ok()
     }
 
-    fn manage_address_input(self: &mut Self) -> String {
-        if self.device_address.len() > 0 {
+    fn manage_address_input(&mut self) -> String {
+        if !self.device_address.is_empty() {
             match u32::from_str_radix(self.device_address.as_str(), 16) {
                 Ok(_) => self.device_address.clone(),
                 Err(_) => self.valid_device_address.clone(),
@@ -147,3 +726,111 @@ impl App {
         }
     }
 }
+
+/// Round trip of the last command on a channel, or its error.
+fn channel_status(ui: &mut egui::Ui, status: &ChannelStatus) {
+    match status {
+        ChannelStatus::Idle => ui.label("-"),
+        ChannelStatus::Ok(round_trip) => ui.colored_label(
+            egui::Color32::GREEN,
+            format!("ok {} ms", round_trip.as_millis()),
+        ),
+        ChannelStatus::Failed(e) => ui.colored_label(egui::Color32::RED, e),
+    };
+}
+
+fn status_color(status: StepStatus) -> egui::Color32 {
+    match status {
+        StepStatus::Pending => egui::Color32::GRAY,
+        StepStatus::Running => egui::Color32::YELLOW,
+        StepStatus::Pass => egui::Color32::GREEN,
+        StepStatus::Fail => egui::Color32::RED,
+    }
+}
+
+/// The steps of the last test with their outcome, updated while it runs.
+fn test_checklist(ui: &mut egui::Ui, run: &TestRun) {
+    ui.horizontal(|ui| {
+        ui.label(format!("{} su {:08X}", run.plan, run.address));
+        if let Some(duration) = run.duration {
+            ui.label(format!("{} ms", duration.as_millis()));
+        }
+        for (relay, result) in run.relays.iter().enumerate() {
+            let status = match result {
+                RelayResult::Untested => StepStatus::Pending,
+                RelayResult::Pass => StepStatus::Pass,
+                RelayResult::Fail => StepStatus::Fail,
+            };
+            ui.colored_label(status_color(status), format!("rele' {}", relay));
+        }
+    });
+
+    for fault in &run.faults {
+        ui.colored_label(egui::Color32::RED, fault.to_string());
+    }
+
+    egui::ScrollArea::vertical()
+        .id_source("test_run")
+        .max_height(160.)
+        .show(ui, |ui| {
+            egui::Grid::new("test_steps").striped(true).show(ui, |ui| {
+                for step in &run.steps {
+                    ui.colored_label(status_color(step.status), "\u{25CF}");
+                    ui.label(&step.description);
+                    ui.label(match step.inputs {
+                        Some(InputCheck {
+                            expected,
+                            actual: Some(actual),
+                            ..
+                        }) => format!("attesi 0x{:02X}, letti 0x{:02X}", expected, actual),
+                        Some(InputCheck { expected, .. }) => format!("attesi 0x{:02X}", expected),
+                        None => String::new(),
+                    });
+                    ui.label(match step.duration {
+                        Some(duration) => format!("{} ms", duration.as_millis()),
+                        None => String::new(),
+                    });
+                    match &step.error {
+                        Some(error) => ui.colored_label(egui::Color32::RED, error.to_string()),
+                        None => ui.label(""),
+                    };
+                    ui.end_row();
+                }
+            });
+        });
+}
+
+fn default_address() -> u32 {
+    u32::from_str_radix(DEFAULT_ADDRESS, 16).unwrap()
+}
+
+/// Parses a comma separated list of hex addresses and inclusive ranges.
+fn parse_addresses(text: &str) -> Result<Vec<u32>, String> {
+    let parse = |s: &str| {
+        u32::from_str_radix(s.trim(), 16).map_err(|_| format!("Indirizzo non valido: {}", s))
+    };
+
+    let mut addresses = Vec::new();
+    for item in text.split(',').filter(|item| !item.trim().is_empty()) {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last || (last - first) as usize >= MAX_SCAN_ADDRESSES {
+                    return Err(format!("Intervallo non valido: {}", item));
+                }
+                addresses.extend(first..=last);
+            }
+            None => addresses.push(parse(item)?),
+        }
+
+        if addresses.len() > MAX_SCAN_ADDRESSES {
+            return Err(String::from("Troppi indirizzi"));
+        }
+    }
+
+    if addresses.is_empty() {
+        Err(String::from("Nessun indirizzo"))
+    } else {
+        Ok(addresses)
+    }
+}
@@ -1,11 +1,41 @@
 pub mod app;
 
+use crate::controller::console::{Favorite, RawRequest};
+use crate::controller::traffic::Filter;
+use crate::model::PortSettings;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
 pub enum Message {
-    ConnectToPort(String),
+    ConnectToPort(String, PortSettings),
+    Disconnect,
+    AutoReconnect(bool),
     SetSerialNumber(u32),
+    AssignSerialNumber,
     ReadSerialNumber(u32),
     ReadFWVersion(u32),
+    ReadCpuId(u32),
     DeviceAddress(String),
     Test(u32),
+    /// Go on after a failed step in the next tests
+    RunAllSteps(bool),
+    Scan(Vec<u32>),
+    /// Stops the running job at its next step
+    Stop,
+    /// Polls the inputs of the board every given ms while the manual panel is
+    /// open; `None` closes it and switches off the relays it left on
+    ManualIo(Option<(u32, u64)>),
+    /// Switches a relay of the board in the manual panel
+    SetRelay(u8, bool),
+    /// Sent when the window closes, answered once the boards are left safe
+    Shutdown(mpsc::Sender<()>),
+    PauseTraffic(bool),
+    ClearTraffic,
+    ExportTraffic(PathBuf, Filter),
+    /// Starts saving the raw bytes to the file, or stops with `None`
+    Capture(Option<PathBuf>),
+    SendRaw(RawRequest),
+    /// Saves the favorite, replacing the one with the same name
+    SaveFavorite(Favorite),
+    RemoveFavorite(String),
 }
-